@@ -0,0 +1,38 @@
+//! Feeds arbitrary lines to the parsers: they must never panic, and whatever they accept must
+//! come back unchanged when written out and parsed again.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Only part of the protocol is exercised here
+#[allow(dead_code)]
+#[path = "../../src/protocol.rs"]
+mod protocol;
+
+use protocol::{parse_client, parse_server};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(input) = std::str::from_utf8(data) else { return };
+    for line in input.lines() {
+        if let Some(req) = parse_client(line) {
+            let wire = req.to_wire();
+            assert_eq!(
+                parse_client(wire.strip_suffix('\n').unwrap()),
+                Some(req),
+                "{:?}",
+                line
+            );
+        }
+        if let Some(req) = parse_server(line) {
+            let wire = req.to_wire();
+            assert_eq!(
+                parse_server(wire.strip_suffix('\n').unwrap()),
+                Some(req),
+                "{:?}",
+                line
+            );
+        }
+        let _ = protocol::parse(line);
+    }
+});
@@ -0,0 +1,217 @@
+//! Builds valid requests out of the fuzzer's bytes and checks each parses back from its wire
+//! form field for field, so a writer and parser that disagree on argument order are caught.
+
+#![no_main]
+
+use libfuzzer_sys::arbitrary::{Result, Unstructured};
+use libfuzzer_sys::fuzz_target;
+
+// Only part of the protocol is exercised here
+#[allow(dead_code)]
+#[path = "../../src/protocol.rs"]
+mod protocol;
+
+use protocol::{
+    decode_client, encode_client, parse_client, parse_server, ClientRequest, ServerRequest,
+    ServerResult, MAX_TOKEN,
+};
+
+/// A single argument: never empty, never containing a space or line break, and never longer
+/// than the parser takes a username or password to be.
+fn word(u: &mut Unstructured) -> Result<String> {
+    let s: String = u.arbitrary()?;
+    let mut s: String = s
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '\n' | '\r'))
+        .collect();
+    while s.len() > MAX_TOKEN {
+        s.pop();
+    }
+    Ok(if s.is_empty() { String::from("x") } else { s })
+}
+
+/// The free text that ends a line, which may contain spaces.
+fn tail(u: &mut Unstructured) -> Result<String> {
+    let s: String = u.arbitrary()?;
+    Ok(s.chars().filter(|c| !matches!(c, '\n' | '\r')).collect())
+}
+
+fn status(u: &mut Unstructured) -> Result<&'static str> {
+    Ok(if u.arbitrary()? { "1" } else { "0" })
+}
+
+fn check_client(u: &mut Unstructured) -> Result<()> {
+    use ClientRequest::*;
+
+    let (a, b, c, d) = (word(u)?, word(u)?, tail(u)?, word(u)?);
+    let req = match u.int_in_range(0..=39)? {
+        0 => Register {
+            username: &a,
+            password: &b,
+        },
+        1 => Login {
+            username: &a,
+            password: &b,
+        },
+        2 => Join { channel: &a },
+        3 => Create { channel: &a },
+        4 => Say {
+            channel: &a,
+            message: &c,
+        },
+        5 => Channels,
+        6 => Report {
+            channel: &a,
+            user: &b,
+            reason: &c,
+        },
+        7 => Ignore { user: &a },
+        8 => Unignore { user: &a },
+        9 => Who {
+            channel: &a,
+            page: None,
+        },
+        10 => Limits,
+        11 => SetName { display: &a },
+        12 => Pending,
+        13 => Fetch,
+        14 => Alias { old: &a, new: &b },
+        15 => Pause,
+        16 => Resume,
+        17 => Servers,
+        18 => Defed { addr: &a },
+        19 => Set {
+            key: &a,
+            value: &c,
+        },
+        20 => Get { key: &a },
+        21 => Info { channel: &a },
+        22 => Mode {
+            channel: &a,
+            mode: &b,
+            arg: None,
+        },
+        23 => Mode {
+            channel: &a,
+            mode: &b,
+            arg: Some(&d),
+        },
+        24 => FedPingTest { addr: &a },
+        25 => Invite {
+            channel: &a,
+            user: &b,
+        },
+        26 => JoinAll { channels: &a },
+        27 => Motd,
+        28 => ChanStats { channel: &a },
+        29 => Profile { user: &a },
+        30 => Remove {
+            channel: &a,
+            user: &b,
+        },
+        31 => Snapshot {
+            offset: u.int_in_range(0..=u32::MAX)? as usize,
+        },
+        32 => Migrate {
+            user: &a,
+            addr: &b,
+        },
+        33 => Modes { channel: &a },
+        34 => CancelJoin { channel: &a },
+        35 => Capacity,
+        36 => Reply {
+            channel: &a,
+            parent: u.int_in_range(0..=u32::MAX)? as u64,
+            message: &c,
+        },
+        37 => InfoAll { channels: &a },
+        38 => Reports,
+        _ => Who {
+            channel: &a,
+            page: Some((
+                u.int_in_range(0..=u32::MAX)? as usize,
+                u.int_in_range(0..=u32::MAX)? as usize,
+            )),
+        },
+    };
+    let (wire, frame) = (req.to_wire(), encode_client(&req));
+    assert_eq!(decode_client(&frame[4..]).as_ref(), Some(&req));
+    assert_eq!(parse_client(wire.strip_suffix('\n').unwrap()), Some(req));
+    Ok(())
+}
+
+fn check_server(u: &mut Unstructured) -> Result<()> {
+    use ServerRequest::*;
+
+    let (a, b, c, d) = (word(u)?, word(u)?, word(u)?, tail(u)?);
+    let parent = if u.arbitrary()? {
+        Some(u.int_in_range(0..=u32::MAX)? as u64)
+    } else {
+        None
+    };
+    let req = match u.int_in_range(0..=16)? {
+        0 => Out { addr: None },
+        1 => Out { addr: Some(&a) },
+        2 => Reject { reason: &d },
+        3 => Confirm,
+        4 => Channels { channels: &d },
+        5 => Sync { channels: &d },
+        6 => New { channel: &a },
+        7 => Join {
+            user: &a,
+            channel: &b,
+        },
+        8 => Presence {
+            user: &a,
+            channel: &b,
+        },
+        9 => Say {
+            user: &a,
+            channel: &b,
+            parent,
+            msg: &d,
+        },
+        10 => Recv {
+            to_user: &a,
+            from_user: &b,
+            channel: &c,
+            parent,
+            msg: &d,
+        },
+        11 => Result(ServerResult::Join {
+            user: &a,
+            channel: &b,
+            status: status(u)?,
+        }),
+        12 => Result(ServerResult::Say {
+            user: &a,
+            channel: &b,
+            status: status(u)?,
+            msg: &d,
+        }),
+        13 => Ping { token: &a },
+        14 => Pong { token: &a },
+        15 => Leave {
+            user: &a,
+            channel: &b,
+        },
+        _ => Bye,
+    };
+    let wire = req.to_wire();
+    assert_eq!(parse_server(wire.strip_suffix('\n').unwrap()), Some(req));
+    Ok(())
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut u = Unstructured::new(data);
+    while !u.is_empty() {
+        let checked = if u.arbitrary().unwrap_or(false) {
+            check_client(&mut u)
+        } else {
+            check_server(&mut u)
+        };
+        if checked.is_err() {
+            break;
+        }
+    }
+});
@@ -1,209 +1,405 @@
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+mod protocol;
+mod websocket;
+
+use protocol::{
+    decode_client, parse, parse_client, parse_server, ClientRequest, ConnectionKind, Request,
+    ServerRequest, ServerResult,
+};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::broadcast;
-use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::{broadcast, watch, Notify};
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io::Write;
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-enum ClientRequest<'a> {
-    Register {
-        username: &'a str,
-        password: &'a str,
-    },
-    Login {
-        username: &'a str,
-        password: &'a str,
-    },
-    Join {
-        channel: &'a str,
-    },
-    Create {
-        channel: &'a str,
-    },
-    Say {
-        channel: &'a str,
-        message: &'a str,
-    },
-    Channels,
-}
+type ClientChannel = Arc<ClientQueue>;
 
-enum ServerResult<'a> {
-    Join {
-        user: &'a str,
-        channel: &'a str,
-        status: &'a str,
-    },
-    Say {
-        user: &'a str,
-        channel: &'a str,
-        status: &'a str,
-        msg: &'a str,
-    },
+/// What a client's connection is handed to pass on.
+#[derive(Debug)]
+enum ClientMessage {
+    /// Written out as it is, or held while the client is paused
+    Message(Arc<String>),
+    /// Sent on as `REDIRECT <addr>` whether or not the client is paused, and then the
+    /// connection is closed
+    Redirect(String),
+    /// Takes the last place in a full queue. The client is told it lagged, and the connection
+    /// is closed.
+    Lagged,
+    /// Sent on as `REMOVED <channel>`, once the connection stops hearing the channel
+    Removed(String),
 }
 
-enum ServerRequest<'a> {
-    Out,
-    Confirm,
-    Channels {
-        channels: &'a str,
-    },
-    New {
-        channel: &'a str,
-    },
-    Join {
-        user: &'a str,
-        channel: &'a str,
-    },
-    Say {
-        user: &'a str,
-        channel: &'a str,
-        msg: &'a str,
-    },
-    Recv {
-        to_user: &'a str,
-        from_user: &'a str,
-        channel: &'a str,
-        msg: &'a str,
-    },
-    Result(ServerResult<'a>),
+/// What's waiting to be written to one client, at most `--client-queue` of it. Sending never
+/// waits, so nobody is held up by a client that's slow to read, or by one that's itself waiting
+/// to send: a client whose queue fills up has lagged, and is let go once it reaches the end of
+/// it. Nothing more is queued for it meanwhile, and members are sent to their inbox instead.
+#[derive(Debug)]
+struct ClientQueue {
+    sender: mpsc::Sender<ClientMessage>,
+    lagged: AtomicBool,
 }
 
-enum Request<'a> {
-    Client(ClientRequest<'a>),
-    Server(ServerRequest<'a>),
-}
+impl ClientQueue {
+    fn new(capacity: usize) -> (Self, mpsc::Receiver<ClientMessage>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let queue = ClientQueue {
+            sender,
+            lagged: AtomicBool::new(false),
+        };
+        (queue, receiver)
+    }
+
+    /// Queues a message, or gives it back if the connection is closed or has lagged.
+    fn send(&self, message: ClientMessage) -> Result<(), ClientMessage> {
+        if self.lagged.load(Ordering::SeqCst) {
+            return Err(message);
+        }
+        let Ok(permit) = self.sender.try_reserve() else {
+            return Err(message);
+        };
+        if self.sender.capacity() > 0 {
+            permit.send(message);
+            return Ok(());
+        }
+        self.lagged.store(true, Ordering::SeqCst);
+        permit.send(ClientMessage::Lagged);
+        Err(message)
+    }
 
-fn two(input: &str) -> Option<(&str, &str)> {
-    input.split_once(' ').filter(|(_, b)| !b.contains(' '))
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
 }
 
-fn parse_client(input: &str) -> Option<ClientRequest<'_>> {
-    use ClientRequest::*;
+/// The first line a client sends to speak the binary framing instead of text.
+const BINARY_MODE: &str = "MODE BINARY";
+/// The largest binary frame a client may send.
+const MAX_FRAME: usize = 64 * 1024;
 
-    let (kind, args) = input.split_once(' ').unwrap_or((&*input, ""));
-    let req = match kind {
-        "REGISTER" => {
-            let (username, password) = two(args)?;
-            Register { username, password }
-        }
-        "LOGIN" => {
-            let (username, password) = two(args)?;
-            Login { username, password }
+/// The longest line anyone may send, in bytes, however long `--max-message` lets messages be.
+const MAX_LINE: usize = 4 * 1024 * 1024;
+
+/// Reads lines like tokio's `Lines`, but fails on one longer than `MAX_LINE` rather than
+/// buffering however much is sent without a line break. A partial line is kept buffered, so
+/// nothing is lost when a read is cancelled partway through.
+struct LineReader {
+    reader: BufReader<OwnedReadHalf>,
+    line: Vec<u8>,
+}
+
+impl LineReader {
+    fn new(reader: OwnedReadHalf) -> Self {
+        LineReader {
+            reader: BufReader::new(reader),
+            line: Vec::new(),
         }
-        "JOIN" => {
-            if args.contains(' ') {
-                return None;
+    }
+
+    async fn next_line(&mut self) -> std::io::Result<Option<String>> {
+        let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        loop {
+            let available = self.reader.fill_buf().await?;
+            if available.is_empty() {
+                if self.line.is_empty() {
+                    return Ok(None);
+                }
+                break;
             }
-            Join { channel: args }
-        }
-        "CREATE" => {
-            if args.contains(' ') {
-                return None;
+            if let Some(end) = available.iter().position(|&b| b == b'\n') {
+                self.line.extend_from_slice(&available[..end]);
+                self.reader.consume(end + 1);
+                break;
+            }
+            let len = available.len();
+            self.line.extend_from_slice(available);
+            self.reader.consume(len);
+            if self.line.len() > MAX_LINE {
+                return Err(invalid("line too long"));
             }
-            Create { channel: args }
         }
-        "SAY" => {
-            let (channel, message) = args.split_once(' ')?;
-            Say { channel, message }
+        let mut line = std::mem::take(&mut self.line);
+        if line.last() == Some(&b'\r') {
+            line.pop();
         }
-        "CHANNELS" => Channels,
-        _ => return None,
-    };
+        if line.len() > MAX_LINE {
+            return Err(invalid("line too long"));
+        }
+        String::from_utf8(line)
+            .map(Some)
+            .map_err(|_| invalid("not UTF-8"))
+    }
+
+    fn get_ref(&self) -> &OwnedReadHalf {
+        self.reader.get_ref()
+    }
 
-    Some(req)
+    /// The reader, with whatever it has buffered past the last line.
+    fn into_inner(self) -> BufReader<OwnedReadHalf> {
+        self.reader
+    }
 }
 
-fn parse_server(input: &str) -> Option<ServerRequest<'_>> {
-    use ServerRequest::*;
+/// Reads binary frames, keeping partial ones buffered so that, like `LineReader`, nothing is
+/// lost when a read is cancelled partway through.
+struct Frames {
+    reader: BufReader<OwnedReadHalf>,
+    buf: Vec<u8>,
+}
 
-    let (kind, args) = input.split_once(' ').unwrap_or((&*input, ""));
-    let req = match kind {
-        "FEDOUT" => Out,
-        "FEDCONFIRM" => Confirm,
-        "FEDCHANNELS" => Channels { channels: args },
-        "FEDNEW" => {
-            if args.contains(' ') {
-                return None;
+impl Frames {
+    async fn next_frame(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(len) = self.buf.get(..4) {
+                let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+                if len > MAX_FRAME {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "frame too large",
+                    ));
+                }
+                if self.buf.len() >= 4 + len {
+                    let frame = self.buf[4..4 + len].to_vec();
+                    self.buf.drain(..4 + len);
+                    return Ok(Some(frame));
+                }
             }
-            New { channel: args }
-        }
-        "FEDJOIN" => {
-            let (user, channel) = two(args)?;
-            Join { user, channel }
-        }
-        "FEDSAY" => {
-            let (user, args) = args.split_once(' ')?;
-            let (channel, msg) = args.split_once(' ')?;
-            Say { user, channel, msg }
-        }
-        "FEDRECV" => {
-            let (to_user, args) = args.split_once(' ')?;
-            let (from_user, args) = args.split_once(' ')?;
-            let (channel, msg) = args.split_once(' ')?;
-            Recv {
-                to_user,
-                from_user,
-                channel,
-                msg,
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                return Ok(None);
             }
         }
-        "FEDRESULT" => {
-            let (user, args) = args.split_once(' ')?;
-            let (kind, args) = args.split_once(' ')?;
-            let (channel, args) = args.split_once(' ')?;
-            match kind {
-                "JOIN" => {
-                    if !matches!(args, "0" | "1") {
-                        return None;
+    }
+}
+
+/// Reads a WebSocket's text messages, each of which is one line. Like `Frames`, partial frames
+/// and messages stay buffered across cancelled reads.
+struct WebSocketFrames {
+    reader: BufReader<OwnedReadHalf>,
+    buf: Vec<u8>,
+    /// The fragments of a message whose last frame hasn't arrived yet
+    message: Vec<u8>,
+}
+
+impl WebSocketFrames {
+    async fn next_frame(&mut self) -> std::io::Result<Option<Frame>> {
+        let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        loop {
+            let Some((frame, len)) =
+                websocket::parse_frame(&self.buf, MAX_FRAME).map_err(invalid)?
+            else {
+                if self.reader.read_buf(&mut self.buf).await? == 0 {
+                    return Ok(None);
+                }
+                continue;
+            };
+            self.buf.drain(..len);
+            match frame.opcode {
+                websocket::PING => return Ok(Some(Frame::Ping(frame.payload))),
+                // The client is going away, and waits on our close frame before it hangs up
+                websocket::CLOSE => return Ok(Some(Frame::Close(frame.payload))),
+                websocket::TEXT | websocket::CONTINUATION => {
+                    self.message.extend_from_slice(&frame.payload);
+                    if self.message.len() > MAX_FRAME {
+                        return Err(invalid("message too large"));
                     }
-                    Result(ServerResult::Join {
-                        user,
-                        channel,
-                        status: args,
-                    })
-                }
-                "SAY" => {
-                    let (status, msg) = args.split_once(' ')?;
-                    if !matches!(status, "0" | "1") {
-                        return None;
+                    if !frame.fin {
+                        continue;
                     }
-                    Result(ServerResult::Say {
-                        user,
-                        channel,
-                        status,
-                        msg,
-                    })
+                    let message = std::mem::take(&mut self.message);
+                    let message = String::from_utf8(message).map_err(|_| invalid("not UTF-8"))?;
+                    let line = message.strip_suffix('\n').unwrap_or(&message);
+                    // More than one line in a message is nothing we could have sent
+                    if line.contains(['\n', '\r']) {
+                        continue;
+                    }
+                    return Ok(Some(Frame::Text(line.to_string())));
                 }
-                _ => return None,
+                // Pongs, and binary messages, which the text protocol has no use for
+                _ => continue,
             }
         }
-        _ => return None,
-    };
+    }
+}
+
+/// What a client sends, in whichever framing it chose.
+enum ClientInput {
+    Text(LineReader),
+    Binary(Frames),
+    WebSocket(WebSocketFrames),
+}
 
-    Some(req)
+enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+    /// A WebSocket ping, to be answered with the same payload
+    Ping(Vec<u8>),
+    /// A WebSocket close, to be answered with one of our own
+    Close(Vec<u8>),
 }
 
-fn parse(input: &str) -> Option<Request<'_>> {
-    parse_client(input)
-        .map(Request::Client)
-        .or_else(|| parse_server(input).map(Request::Server))
+impl ClientInput {
+    async fn next(&mut self) -> std::io::Result<Option<Frame>> {
+        match self {
+            ClientInput::Text(lines) => Ok(lines.next_line().await?.map(Frame::Text)),
+            ClientInput::Binary(frames) => Ok(frames.next_frame().await?.map(Frame::Binary)),
+            ClientInput::WebSocket(frames) => frames.next_frame().await,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Framing {
+    Text,
+    Binary,
+    /// A text message per line, without its newline
+    WebSocket,
 }
 
-type ClientChannel = Arc<UnboundedSender<Arc<String>>>;
+/// Writes to a client in whichever framing it chose. Writes to a client that has gone away
+/// fail quietly, as reading from it is what ends the connection. What's written is buffered
+/// until `flush`, so that replies and messages ready at the same time go out together.
+struct ClientOutput {
+    writer: BufWriter<ClientWriter>,
+    framing: Framing,
+}
+
+/// Where a client's output goes, which is its socket outside of tests. Boxed, so tests can see
+/// what reaches it.
+type ClientWriter = Pin<Box<dyn AsyncWrite + Send + Sync>>;
+
+impl ClientOutput {
+    fn new(writer: impl AsyncWrite + Send + Sync + 'static, framing: Framing) -> Self {
+        ClientOutput {
+            writer: BufWriter::new(Box::pin(writer)),
+            framing,
+        }
+    }
+
+    /// Whether anything written is still waiting to be sent.
+    fn buffered(&self) -> bool {
+        !self.writer.buffer().is_empty()
+    }
+
+    async fn flush(&mut self) {
+        let _ = self.writer.flush().await;
+    }
+
+    /// Writes one or more newline terminated lines.
+    async fn write(&mut self, msg: &str) {
+        let mut frames = Vec::with_capacity(msg.len() + 4);
+        match self.framing {
+            Framing::Text => frames.extend_from_slice(msg.as_bytes()),
+            Framing::Binary => {
+                for line in msg.lines() {
+                    frames.extend_from_slice(&(line.len() as u32).to_be_bytes());
+                    frames.extend_from_slice(line.as_bytes());
+                }
+            }
+            Framing::WebSocket => {
+                for line in msg.lines() {
+                    frames.append(&mut websocket::encode(websocket::TEXT, line.as_bytes()));
+                }
+            }
+        }
+        let _ = self.writer.write_all(&frames).await;
+    }
+
+    async fn pong(&mut self, payload: &[u8]) {
+        let frame = websocket::encode(websocket::PONG, payload);
+        let _ = self.writer.write_all(&frame).await;
+    }
+
+    /// Answers a client's close frame, echoing the status code it gave, if any.
+    async fn close(&mut self, payload: &[u8]) {
+        let frame = websocket::encode(websocket::CLOSE, payload.get(..2).unwrap_or_default());
+        let _ = self.writer.write_all(&frame).await;
+    }
+}
 
 struct ClientConnection {
     username: Option<Arc<String>>,
+    /// The name the user chose to be shown as for the rest of this session, if any
+    display: Option<String>,
     channel: ClientChannel,
-    server_addr: SocketAddr,
+    /// Where the client is connecting from
+    peer_addr: SocketAddr,
+    /// When each `SAY` in the last second was accepted, for rate limiting
+    recent_says: VecDeque<Instant>,
+    /// While paused, the pushed messages held back until the client resumes
+    paused: Option<VecDeque<Arc<String>>>,
+    /// The fan-out of each channel joined on this connection, used by the large ones
+    subscriptions: Subscriptions,
+}
+
+/// A message sent to all of a large channel's local members at once. Each member's connection
+/// checks for itself whether it's still in the channel, which its subscription is named for, and
+/// whether it ignores the sender.
+struct Broadcast {
+    from: String,
+    message: Arc<String>,
+}
+
+type Received = Result<Arc<Broadcast>, broadcast::error::RecvError>;
+
+/// A subscription waiting on its next message, which gives the receiver back with it.
+type Receiving =
+    Pin<Box<dyn Future<Output = (Received, broadcast::Receiver<Arc<Broadcast>>)> + Send + Sync>>;
+
+fn receiving(mut receiver: broadcast::Receiver<Arc<Broadcast>>) -> Receiving {
+    Box::pin(async move { (receiver.recv().await, receiver) })
 }
 
-#[derive(PartialEq, Eq, Hash, Debug)]
+/// A connection's subscriptions, one for each channel by its name. Each waits on its next message
+/// for as long as it takes, rather than being started over every time the connection waits on
+/// something else too.
+#[derive(Default)]
+struct Subscriptions(HashMap<String, Receiving>);
+
+impl Subscriptions {
+    /// Subscribes to a channel, in place of whatever subscription to it there was before.
+    fn insert(&mut self, channel: &str, receiver: broadcast::Receiver<Arc<Broadcast>>) {
+        self.0.insert(channel.to_string(), receiving(receiver));
+    }
+
+    fn remove(&mut self, channel: &str) {
+        self.0.remove(channel);
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Waits for a message on any of them, giving which channel it came on.
+    async fn next(&mut self) -> (String, Received) {
+        std::future::poll_fn(|cx| {
+            for (channel, waiting) in self.0.iter_mut() {
+                if let std::task::Poll::Ready((msg, receiver)) = waiting.as_mut().poll(cx) {
+                    *waiting = receiving(receiver);
+                    return std::task::Poll::Ready((channel.clone(), msg));
+                }
+            }
+            std::task::Poll::Pending
+        })
+        .await
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
 enum Response {
     Join { channel: String },
     Say { channel: String, message: String },
+    Ping { token: String },
 }
 
 #[derive(Debug)]
@@ -215,21 +411,205 @@ enum ServerMessage {
         response: Response,
         message: String,
     },
+    /// Stops a client waiting on the peer's answer, telling it so on `channel`
+    Cancel {
+        channel: ClientChannel,
+        key: CallbackKey,
+    },
+}
+
+type ServerChannel = Arc<PeerQueue>;
+
+/// What's waiting to be written to one peer, at most `--peer-queue` of it. Like a client that
+/// lags, a peer that falls a whole queue behind has missed something: its link is dropped as
+/// soon as it does, and dialed again, so both ends start over from a fresh handshake.
+struct PeerQueue {
+    sender: mpsc::Sender<ServerMessage>,
+    /// Tells the link's task its queue overflowed
+    lagged: Notify,
+}
+
+impl PeerQueue {
+    fn new(capacity: usize) -> (Self, mpsc::Receiver<ServerMessage>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let queue = PeerQueue {
+            sender,
+            lagged: Notify::new(),
+        };
+        (queue, receiver)
+    }
+
+    fn try_send(&self, message: ServerMessage) -> Result<(), TrySendError<ServerMessage>> {
+        let sent = self.sender.try_send(message);
+        if let Err(TrySendError::Full(_)) = sent {
+            self.lagged.notify_one();
+        }
+        sent
+    }
+
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+/// Queues a message on a federation link without waiting. Every link drains its own bounded
+/// queue in its own task, so a slow peer only holds up deliveries to itself. A message that
+/// doesn't fit is dropped, and the link with it, rather than blocking whoever is sending. Gives
+/// whether the link is still open.
+fn send_to_peer(peer: &ServerChannel, message: Arc<String>) -> bool {
+    match peer.try_send(ServerMessage::Message(message)) {
+        Err(TrySendError::Full(_)) => {
+            eprintln!("Dropped a message for a peer whose queue is full");
+            true
+        }
+        Err(TrySendError::Closed(_)) => false,
+        Ok(()) => true,
+    }
+}
+
+/// Queues something a client waits on the peer's answer to, like `send_to_peer`. It's never
+/// dropped without a word, as the client would wait forever: gives why it couldn't be queued,
+/// for the client to be told instead.
+fn ask_peer(peer: &ServerChannel, message: ServerMessage) -> Result<(), &'static str> {
+    match peer.try_send(message) {
+        Err(TrySendError::Full(_)) => Err("server-busy"),
+        Err(TrySendError::Closed(_)) => Err("server-gone"),
+        Ok(()) => Ok(()),
+    }
+}
+
+/// Forgets a peer whose link was found closed before its task forgot it, so nothing more is
+/// routed to it. Nothing is done if another link has taken over since.
+fn forget_closed(server: &Server, addr: SocketAddr, dead: &ServerChannel) {
+    let mut servers = server.servers.write().unwrap();
+    if servers
+        .get(&addr)
+        .is_some_and(|r| Arc::ptr_eq(&r.channel, dead))
+    {
+        servers.remove(&addr);
+    }
+}
+
+/// How far along the federation handshake a link is. The dialing server sends `FEDOUT` with the
+/// address it listens on, the listening server answers `FEDCONFIRM`, and the dialer finishes
+/// with `FEDCHANNELS`. Either side may instead answer `FEDREJECT <reason>` and hang up.
+#[derive(Clone, Copy)]
+enum Handshake {
+    /// We were dialed and are waiting for the peer's `FEDOUT`
+    AwaitingOut,
+    /// We dialed and are waiting for the peer's `FEDCONFIRM`
+    AwaitingConfirm,
+    /// We confirmed and are waiting for the peer's `FEDCHANNELS`
+    AwaitingChannels,
+    Established,
+    /// One side refused to federate, the link is closing
+    Rejected,
+    /// One side ended the federation with `FEDBYE`, the link is closing
+    Closed,
 }
 
-type ServerChannel = Arc<UnboundedSender<ServerMessage>>;
+impl Handshake {
+    fn closing(self) -> bool {
+        matches!(self, Handshake::Rejected | Handshake::Closed)
+    }
+}
+
+type CallbackKey = (Arc<String>, Response);
+
+/// A client waiting on the peer's `FEDRESULT` for something it asked of it.
+struct Callback {
+    channel: ClientChannel,
+    sent: Instant,
+    deadline: Instant,
+}
 
 struct ServerConnection {
     channel: ServerChannel,
     server_addr: SocketAddr,
-    callbacks: HashMap<(Arc<String>, Response), ClientChannel>,
+    /// Where the peer is connecting from
+    peer_addr: SocketAddr,
+    callbacks: HashMap<CallbackKey, Callback>,
+    /// When each callback gives up waiting, oldest first
+    expiries: VecDeque<(Instant, CallbackKey)>,
+    /// The peer's joins to channels we don't have yet, oldest first
+    held_joins: Vec<HeldJoin>,
+    handshake: Handshake,
+    /// Woken when an admin ends the federation with `DEFED`
+    revoke: Arc<Notify>,
+}
+
+/// A peer's join to a channel we don't have, waiting a moment in case it's being created while
+/// the join was on its way.
+struct HeldJoin {
+    deadline: Instant,
+    user: String,
+    channel: String,
+}
+
+/// How long a peer's join to a channel we don't have yet waits for it to be created.
+const JOIN_GRACE: Duration = Duration::from_secs(2);
+
+/// Answers the held joins that are `ready`, whether or not their channel turned up.
+fn release_joins(
+    server: &Server,
+    conn: &mut ServerConnection,
+    ready: impl Fn(&HeldJoin) -> bool,
+) -> Vec<String> {
+    let (ready, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut conn.held_joins)
+        .into_iter()
+        .partition(ready);
+    conn.held_joins = waiting;
+    ready
+        .into_iter()
+        .map(|held| answer_join(server, conn, &held.user, &held.channel))
+        .collect()
+}
+
+/// The answer a client waiting on a callback gets when the peer never gives one.
+fn callback_failure(response: &Response, peer: SocketAddr, reason: &str) -> String {
+    match response {
+        Response::Join { channel } => format!("RESULT JOIN {}:{} 0 {}\n", channel, peer, reason),
+        Response::Say { channel, .. } => format!("RESULT SAY {}:{} 0 {}\n", channel, peer, reason),
+        Response::Ping { .. } => format!("RESULT FEDPINGTEST {} {}\n", peer, reason),
+    }
+}
+
+/// Answers every callback the peer has taken too long to reply to, so the waiting client isn't
+/// left hanging and the entry doesn't linger forever.
+fn expire_callbacks(conn: &mut ServerConnection) {
+    let now = Instant::now();
+    while conn
+        .expiries
+        .front()
+        .is_some_and(|(deadline, _)| *deadline <= now)
+    {
+        let (deadline, key) = conn.expiries.pop_front().unwrap();
+        // It may have been answered, or retried and given a later deadline, since
+        if conn
+            .callbacks
+            .get(&key)
+            .is_some_and(|c| c.deadline == deadline)
+        {
+            let callback = conn.callbacks.remove(&key).unwrap();
+            let message = callback_failure(&key.1, conn.server_addr, "timeout");
+            let _ = callback
+                .channel
+                .send(ClientMessage::Message(Arc::new(message)));
+        }
+    }
 }
 
 struct RemoteServer {
     channel: ServerChannel,
+    revoke: Arc<Notify>,
+    /// Whether we dialed the peer, rather than it dialing us
+    outbound: bool,
     channels: RwLock<HashSet<String>>,
+    /// The members of the peer's channels that our users are in, learnt through `FEDPRESENCE`
+    members: RwLock<HashMap<String, HashSet<String>>>,
 }
 
+#[derive(Clone)]
 enum User {
     Local(ClientChannel),
     Remote(ServerChannel),
@@ -237,212 +617,2394 @@ enum User {
 
 struct Channel {
     users: HashMap<Arc<String>, User>,
+    /// Whoever created the channel, who may change its modes
+    owner: Option<Arc<String>>,
+    /// The most recent messages said here and when, oldest first, replayed to whoever joins
+    history: Mutex<VecDeque<(Instant, Arc<String>)>>,
+    /// How many messages the owner chose to keep, instead of the server's `--history`
+    retention: Option<usize>,
+    visibility: Visibility,
+    /// Who may join while the channel is private, by the name they join with
+    invited: HashSet<String>,
+    /// How many messages have been said here
+    messages: AtomicU64,
+    created: SystemTime,
+    /// Reaches every local member at once, once there are enough of them
+    local: broadcast::Sender<Arc<Broadcast>>,
+    /// Whether members go without being told who joins and leaves
+    quiet: bool,
 }
 
-struct Server {
-    port: u16,
-    users: RwLock<HashMap<Arc<String>, String>>,
-    user_conns: RwLock<HashMap<Arc<String>, ClientChannel>>,
-    channels: RwLock<HashMap<String, RwLock<Channel>>>,
-    servers: RwLock<HashMap<SocketAddr, RemoteServer>>,
+/// Who can see a channel in `CHANNELS`, and who can join it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Visibility {
+    /// Listed and open to everyone
+    Public,
+    /// Listed, but only the owner, admins and those invited can join
+    Private,
+    /// Not listed, but anyone who knows its name can join
+    Hidden,
 }
 
-impl Server {
-    pub fn new(port: u16) -> Self {
-        Server {
-            port,
+impl Channel {
+    fn new(owner: Option<Arc<String>>) -> Self {
+        Channel {
             users: Default::default(),
-            user_conns: Default::default(),
-            channels: Default::default(),
-            servers: Default::default(),
+            owner,
+            history: Default::default(),
+            retention: None,
+            visibility: Visibility::Public,
+            invited: Default::default(),
+            messages: AtomicU64::new(0),
+            created: SystemTime::now(),
+            local: broadcast::channel(BROADCAST_CAPACITY).0,
+            quiet: false,
         }
     }
-}
 
-fn register(server: &Server, username: &str, password: &str) -> String {
-    let username = username.to_string();
-    // read
-    {
-        if server.users.read().unwrap().contains_key(&username) {
-            return String::from("RESULT REGISTER 0\n");
-        }
+    fn may_join(&self, user: &str, admin: bool) -> bool {
+        self.visibility != Visibility::Private
+            || admin
+            || self.owner.as_deref().is_some_and(|o| o == user)
+            || self.invited.contains(user)
     }
-    // write
-    {
-        server
-            .users
-            .write()
-            .unwrap()
-            .insert(Arc::new(username), password.to_string());
+
+    /// How many of the latest messages are kept for replay.
+    fn retention(&self, config: &Config) -> usize {
+        self.retention.unwrap_or(config.history)
     }
-    String::from("RESULT REGISTER 1\n")
-}
 
-fn login(server: &Server, conn: &mut ClientConnection, username: &str, password: &str) -> String {
-    let username = username.to_string();
-    if let Some((un, pass)) = server.users.read().unwrap().get_key_value(&username) {
-        if pass == password {
-            conn.username = Some(Arc::clone(un));
-            return String::from("RESULT LOGIN 1\n");
+    /// Keeps a message for replay, as said at `now`.
+    fn record(&self, config: &Config, msg: &Arc<String>, now: Instant) {
+        let retention = self.retention(config);
+        if retention == 0 {
+            return;
+        }
+        let mut history = self.history.lock().unwrap();
+        while history.len() >= retention {
+            history.pop_front();
         }
+        history.push_back((now, Arc::clone(msg)));
     }
-    String::from("RESULT LOGIN 0\n")
-}
-
-fn join(server: &Server, conn: &mut ClientConnection, channel: &str) -> String {
-    fn _join(server: &Server, conn: &mut ClientConnection, channel: &str) -> Option<()> {
-        let username = conn.username.as_ref()?;
 
-        let channels = server.channels.read().unwrap();
-        match channel.split_once(':') {
-            Some((channel, remote)) => {
-                let remote: SocketAddr = remote.parse().ok()?;
-                let servers = server.servers.read().unwrap();
-                let remote = servers.get(&remote)?;
-                let message = format!("FEDJOIN {}@{} {}\n", username, conn.server_addr, channel);
-                let message = ServerMessage::CallbackMessage {
-                    channel: Arc::clone(&conn.channel),
-                    user: Arc::clone(username),
-                    response: Response::Join {
-                        channel: channel.to_string(),
-                    },
-                    message,
-                };
-                remote.channel.send(message).unwrap();
-                None
-            }
-            None => {
-                let c = channels.get(channel)?;
-                // read
-                {
-                    if c.read().unwrap().users.contains_key(&*username) {
-                        return None;
-                    }
-                }
-                // write
-                {
-                    c.write()
-                        .unwrap()
-                        .users
-                        .insert(Arc::clone(username), User::Local(Arc::clone(&conn.channel)));
+    /// Tells the local members other than `user` that they joined or left, as `JOINED` or
+    /// `LEFT`, if the server sends such notices and the channel isn't quiet. Those offline miss
+    /// it.
+    fn notify(&self, config: &Config, event: &str, user: &str, channel: &str) {
+        if !config.join_notices || self.quiet {
+            return;
+        }
+        let notice = Arc::new(format!("{} {} {}\n", event, user, channel));
+        for (name, member) in &self.users {
+            if let User::Local(client) = member {
+                if **name != user {
+                    let _ = client.send(ClientMessage::Message(Arc::clone(&notice)));
                 }
-                Some(())
             }
         }
     }
 
-    let status = _join(server, conn, channel).map_or(0, |_| 1);
-    format!("RESULT JOIN {} {}\n", channel, status)
+    /// The retained messages young enough to replay as of `now`, one per line.
+    fn replay(&self, config: &Config, now: Instant) -> String {
+        let history = self.history.lock().unwrap();
+        history
+            .iter()
+            .filter(|(said, _)| {
+                config
+                    .history_max_age
+                    .is_none_or(|age| now.saturating_duration_since(*said) <= age)
+            })
+            .map(|(_, msg)| msg.as_str())
+            .collect()
+    }
 }
 
-fn create(server: &Server, channel: &str) -> String {
-    // read
-    {
-        if server.channels.read().unwrap().contains_key(channel) {
-            // fail
-            return format!("RESULT CREATE {} 0\n", channel);
+/// The name used as the sender of messages generated by the server itself.
+const SYSTEM_USER: &str = "system";
+/// The pseudo-channel that moderation notices are delivered on.
+const ADMIN_CHANNEL: &str = "admin";
+/// The longest display name a user can pick with `SETNAME`.
+const MAX_DISPLAY_NAME: usize = 32;
+/// The most channels a single `JOINALL` can join.
+const MAX_JOIN_ALL: usize = 32;
+/// The most channels a single `INFOALL` can describe.
+const MAX_INFO_ALL: usize = 32;
+/// The most members a single page of `WHO` lists.
+const MAX_WHO_PAGE: usize = 100;
+/// The most channels a single page of `SNAPSHOT` lists.
+const MAX_SNAPSHOT_PAGE: usize = 100;
+/// How long to stop accepting connections after failing to, which is usually for having run out
+/// of file descriptors. Retrying straight away would only fail again until some are freed.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// How many messages are kept for an offline user before the oldest are dropped.
+const MAX_INBOX: usize = 256;
+/// How many settings each account can keep.
+const MAX_SETTINGS: usize = 32;
+/// The longest setting name.
+const MAX_SETTING_KEY: usize = 32;
+/// The longest setting value.
+const MAX_SETTING_VALUE: usize = 256;
+/// The setting an account makes its profile visible to everyone with, by setting it to `public`.
+const PROFILE_SETTING: &str = "profile";
+/// The setting an account has its messages repeated back in `RESULT SAY` with, by setting it to
+/// `on`, so clients can tell which of their messages succeeded.
+const ECHO_SETTING: &str = "echo";
+/// The setting an account is told it was mentioned in a message with, by setting it to `on`.
+const MENTIONS_SETTING: &str = "mentions";
+/// The most requests from one client handled before their replies are sent regardless.
+const MAX_BATCH: usize = 16;
+/// What the message handing a client off to another server starts with.
+const REDIRECT: &str = "REDIRECT ";
+/// How many messages a paused connection holds before the oldest are dropped.
+const MAX_HELD: usize = 256;
+/// How many messages to a large channel a member can fall behind on before they're dropped.
+const BROADCAST_CAPACITY: usize = 256;
+
+/// What `SAY` does when the sender isn't a member of the channel.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SayMode {
+    /// Fail with a plain `RESULT SAY <channel> 0`.
+    Member,
+    /// Fail, giving `not-in-channel` as the reason.
+    Strict,
+    /// Join the channel on the sender's behalf, then say the message.
+    AutoJoin,
+}
+
+impl std::str::FromStr for SayMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "member" => Ok(SayMode::Member),
+            "strict" => Ok(SayMode::Strict),
+            "auto-join" => Ok(SayMode::AutoJoin),
+            _ => Err(()),
         }
     }
-    // write
-    {
-        server.channels.write().unwrap().insert(
-            channel.to_string(),
-            RwLock::new(Channel {
-                users: Default::default(),
-            }),
-        );
-    }
-    // alert
-    {
-        let alert = Arc::new(format!("FEDNEW {}\n", channel));
-        for remote in server.servers.read().unwrap().values() {
-            remote
-                .channel
-                .send(ServerMessage::Message(Arc::clone(&alert)))
-                .unwrap();
+}
+
+/// What a client is told about a line that isn't a request.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum UnknownCommands {
+    /// Nothing, for clients that send what this server doesn't understand.
+    Ignore,
+    /// `RESULT ERROR unknown-command <verb>`, for finding bugs in clients.
+    Error,
+}
+
+impl std::str::FromStr for UnknownCommands {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(UnknownCommands::Ignore),
+            "error" => Ok(UnknownCommands::Error),
+            _ => Err(()),
         }
     }
-    format!("RESULT CREATE {} 1\n", channel)
 }
 
-fn _say(server: &Server, username: &String, channel_name: &str, msg: &str) -> bool {
-    let channels = server.channels.read().unwrap();
-    let Some(c) = channels.get(channel_name) else { return false };
-    let users = &c.read().unwrap().users;
-
-    if users.contains_key(username) {
-        let local_message = Arc::new(format!("RECV {} {} {}\n", username, channel_name, msg));
-        for (name, user) in users {
-            // @Speed currently we are using an unbounded channel so we don't have to await in
-            // this loop while holding a read lock on users
-            // There may also be a deadlock here if we have two users trying to talk to
-            // each other and this is a bounded channel
-            match user {
-                User::Local(channel) => channel.send(Arc::clone(&local_message)).unwrap(),
-                User::Remote(channel) => {
-                    let remote_message = Arc::new(format!(
-                        "FEDRECV {} {} {} {}\n",
-                        name, username, channel_name, msg
-                    ));
-                    channel
-                        .send(ServerMessage::Message(remote_message))
-                        .unwrap()
-                }
+/// Caps on what clients may do, where zero means unlimited.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+struct Limits {
+    /// The longest message that can be said, in bytes
+    max_message: usize,
+    /// The longest username or channel name, in bytes
+    max_name: usize,
+    /// How many channels may exist on this server
+    max_channels: usize,
+    /// How many clients may be connected at once
+    max_connections: usize,
+    /// How many messages a connection can say per second
+    rate: usize,
+    /// How many lines of any kind a connection can send per second
+    command_rate: usize,
+    /// How many seconds in a row a connection can go over `command_rate` before it's dropped
+    command_strikes: usize,
+}
+
+/// Whether `len` fits within `limit`, where zero means unlimited.
+fn within(len: usize, limit: usize) -> bool {
+    limit == 0 || len <= limit
+}
+
+/// Keeps a connection to `command_rate` lines a second. Each second is counted on its own, and
+/// lines over the limit wait for the next one to start. Every second spent over the limit is a
+/// strike, and a quiet second clears them.
+struct Throttle {
+    start: Instant,
+    count: usize,
+    strikes: usize,
+}
+
+impl Throttle {
+    /// Starts counting with the line that opened the connection.
+    fn new() -> Self {
+        Throttle {
+            start: Instant::now(),
+            count: 1,
+            strikes: 0,
+        }
+    }
+
+    /// Whether `admit` would wait before the next line.
+    fn full(&self, limits: &Limits) -> bool {
+        limits.command_rate != 0
+            && self.start.elapsed() < Duration::from_secs(1)
+            && self.count >= limits.command_rate
+    }
+
+    /// Waits until the next line may be handled, or returns false if the connection has
+    /// been over the limit for too long and should be dropped.
+    async fn admit(&mut self, limits: &Limits) -> bool {
+        if limits.command_rate == 0 {
+            return true;
+        }
+        let second = Duration::from_secs(1);
+        if self.start.elapsed() >= second {
+            self.start = Instant::now();
+            self.count = 0;
+            self.strikes = 0;
+        } else if self.count >= limits.command_rate {
+            self.strikes += 1;
+            if limits.command_strikes != 0 && self.strikes > limits.command_strikes {
+                return false;
             }
+            self.start += second;
+            self.count = 0;
+            tokio::time::sleep_until(self.start.into()).await;
         }
+        self.count += 1;
         true
-    } else {
-        false
     }
 }
 
-fn say(server: &Server, conn: &mut ClientConnection, channel: &str, msg: &str) -> String {
-    let status = conn
-        .username
-        .as_ref()
-        .map(|un| _say(server, un, channel, msg))
-        .unwrap_or(false);
-    format!("RESULT SAY {} {}\n", channel, status as i8)
+struct Config {
+    port: u16,
+    peers: Option<String>,
+    /// The file further options were read from, which is read again on `SIGHUP`
+    config_file: Option<String>,
+    admins: RwLock<HashSet<String>>,
+    /// Flush pushed messages queued while handling a request before reading the next one, so
+    /// pipelining clients see every request's pushes before the following `RESULT`.
+    ordered_results: bool,
+    /// How long to wait for connection tasks to finish after a shutdown is requested.
+    shutdown_grace: Duration,
+    /// How long a new connection has to send its first request before it's closed
+    first_line_timeout: Duration,
+    /// Whether clients may open a WebSocket instead of speaking the protocol over plain TCP
+    websocket: bool,
+    /// How many local members a channel needs before its messages are broadcast to them all at
+    /// once, rather than sent to each in turn. Zero never broadcasts.
+    broadcast_threshold: usize,
+    /// Whether channel members are told who joins and leaves, in channels that aren't quiet
+    join_notices: bool,
+    say_mode: SayMode,
+    /// Only ever applies to clients, as two peers answering each other's errors would never stop
+    unknown_commands: UnknownCommands,
+    limits: RwLock<Limits>,
+    /// How often to resend our channel list to each peer, so their view of it can't drift
+    sync_interval: Option<Duration>,
+    /// How many messages may wait to be written to a single peer
+    peer_queue: usize,
+    /// How many messages may wait to be written to a single client
+    client_queue: usize,
+    /// How long a client waits on a peer's answer to a federated request
+    callback_timeout: Duration,
+    /// How many federated requests may wait on a single peer's answer at once
+    max_pending: usize,
+    /// Where account events are recorded, if anywhere
+    audit_log: Option<String>,
+    /// Where every message said in our channels is recorded, if anywhere
+    message_log: Option<String>,
+    /// The file the message of the day is read from, which is read again on `SIGHUP`
+    motd_file: Option<String>,
+    /// The primary this server mirrors as a read-only replica, if it is one. Only channels and
+    /// their members are mirrored, not accounts, and registering is refused, so nobody can log in
+    /// to a replica: it serves what needs no account, like `CHANNELS`, `WHO` and `INFO`.
+    replica_of: Option<SocketAddr>,
+    /// How many of each channel's latest messages are replayed to those joining it, unless its
+    /// owner chose otherwise
+    history: usize,
+    /// The most messages an owner can have their channel keep
+    max_history: usize,
+    /// How old a message can be and still be replayed
+    history_max_age: Option<Duration>,
+    /// How long an account can go without logging in before it's removed, if ever
+    account_ttl: Option<Duration>,
 }
 
-fn list_channels(server: &Server, s: &mut String) {
-    let channels = server.channels.read().unwrap();
-    for name in channels.keys() {
-        s.push(' ');
-        s.push_str(name);
-        s.push(',');
-    }
-    if !channels.is_empty() {
-        s.pop();
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: 0,
+            peers: None,
+            config_file: None,
+            admins: Default::default(),
+            ordered_results: false,
+            shutdown_grace: Duration::from_secs(10),
+            first_line_timeout: Duration::from_secs(30),
+            websocket: false,
+            broadcast_threshold: 64,
+            join_notices: false,
+            say_mode: SayMode::Member,
+            unknown_commands: UnknownCommands::Ignore,
+            limits: Default::default(),
+            sync_interval: None,
+            peer_queue: 1024,
+            client_queue: 1024,
+            callback_timeout: Duration::from_secs(10),
+            max_pending: 256,
+            audit_log: None,
+            message_log: None,
+            motd_file: None,
+            replica_of: None,
+            history: 0,
+            max_history: 1000,
+            history_max_age: None,
+            account_ttl: None,
+        }
     }
-    s.push('\n');
 }
 
-fn channels(server: &Server) -> String {
-    let mut s = String::from("RESULT CHANNELS");
-    list_channels(server, &mut s);
-    s
+fn parse_value<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for --{}: {}", key, value))
 }
 
-fn fed_out(server: &Server, conn: &mut ServerConnection) -> Option<String> {
-    server.servers.write().unwrap().insert(
-        conn.server_addr,
-        RemoteServer {
-            channel: Arc::clone(&conn.channel),
-            channels: Default::default(),
-        },
-    );
-    Some(String::from("FEDCONFIRM\n"))
+/// A number of seconds, which may have a fraction. Negative, infinite and NaN are refused.
+fn parse_secs(key: &str, value: &str) -> Result<Duration, String> {
+    Duration::try_from_secs_f64(parse_value(key, value)?)
+        .map_err(|_| format!("Invalid value for --{}: {}", key, value))
 }
 
-fn fed_confirm(server: &Server, conn: &mut ServerConnection) -> Option<String> {
-    server.servers.write().unwrap().insert(
+/// Like `parse_secs`, where zero means never.
+fn parse_period(key: &str, value: &str) -> Result<Option<Duration>, String> {
+    let period = parse_secs(key, value)?;
+    Ok((!period.is_zero()).then_some(period))
+}
+
+/// Every option `Config::set` takes.
+const OPTIONS: &[&str] = &[
+    "admins",
+    "ordered-results",
+    "websocket",
+    "broadcast-threshold",
+    "join-notices",
+    "say-mode",
+    "unknown-commands",
+    "max-message",
+    "max-name",
+    "max-channels",
+    "max-connections",
+    "rate",
+    "command-rate",
+    "command-strikes",
+    "peer-queue",
+    "client-queue",
+    "audit-log",
+    "message-log",
+    "motd",
+    "replica-of",
+    "callback-timeout",
+    "max-pending",
+    "history",
+    "max-history",
+    "history-max-age",
+    "account-ttl",
+    "sync-interval",
+    "first-line-timeout",
+    "shutdown-grace",
+];
+
+/// The options that can be changed by reloading the configuration file.
+const RELOADABLE: &[&str] = &[
+    "admins",
+    "max-message",
+    "max-name",
+    "max-channels",
+    "max-connections",
+    "rate",
+    "command-rate",
+    "command-strikes",
+];
+
+/// Reads a configuration file: one `option=value` per line, the same options as the command
+/// line takes without their leading `--`. Blank lines and lines starting with `#` are skipped.
+fn read_config_file(path: &str) -> std::io::Result<Vec<(String, String)>> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (key, value) = line.split_once('=').unwrap_or((line, "true"));
+            (key.trim().to_string(), value.trim().to_string())
+        })
+        .collect())
+}
+
+/// What the environment variables setting options start with. `CHAT_MAX_MESSAGE=100` is the same
+/// as `--max-message=100`.
+const ENV_PREFIX: &str = "CHAT_";
+
+/// The option an environment variable sets, if it's named like one.
+fn env_option(name: &str) -> Option<String> {
+    let key = name.strip_prefix(ENV_PREFIX)?;
+    Some(key.to_lowercase().replace('_', "-"))
+}
+
+/// The option a command line flag sets, if it is one.
+fn flag_option(arg: &str) -> Option<&str> {
+    let flag = arg.strip_prefix("--")?;
+    Some(flag.split_once('=').map_or(flag, |(key, _)| key))
+}
+
+impl Config {
+    /// Puts together the configuration from everywhere it can come from. The command line has the
+    /// port, an optional federation peer file, and any number of `--option=value` flags in any
+    /// position. Boolean flags may omit the value. Each source overrides the ones before it:
+    ///
+    /// 1. the defaults
+    /// 2. the file named by `--config=path`
+    /// 3. `CHAT_*` environment variables, from `env`
+    /// 4. the other command line flags
+    ///
+    /// Variables that set no option are skipped, as others may share the prefix. The options are
+    /// checked once they're all in.
+    pub fn resolve(
+        args: impl Iterator<Item = String>,
+        env: impl Iterator<Item = (String, String)>,
+    ) -> Result<Self, String> {
+        let mut config = Config::default();
+        let mut positional = Vec::new();
+        let mut flags = Vec::new();
+        for arg in args {
+            let Some(flag) = arg.strip_prefix("--") else {
+                positional.push(arg);
+                continue;
+            };
+            let (key, value) = flag.split_once('=').unwrap_or((flag, "true"));
+            if key == "config" {
+                config.config_file = Some(value.to_string());
+            } else {
+                flags.push((key.to_string(), value.to_string()));
+            }
+        }
+
+        if let Some(path) = &config.config_file {
+            let settings = read_config_file(path)
+                .map_err(|e| format!("Invalid configuration file {}: {}", path, e))?;
+            for (key, value) in settings {
+                config.set(&key, &value)?;
+            }
+        }
+        for (name, value) in env {
+            let Some(key) = env_option(&name) else {
+                continue;
+            };
+            if !OPTIONS.contains(&key.as_str()) {
+                eprintln!("Ignoring {}, which sets no option", name);
+                continue;
+            }
+            config.set(&key, &value)?;
+        }
+        for (key, value) in flags {
+            config.set(&key, &value)?;
+        }
+
+        let mut positional = positional.into_iter();
+        config.port = positional
+            .next()
+            .ok_or("Missing port number")?
+            .parse()
+            .map_err(|_| "Invalid port number")?;
+        config.peers = positional.next();
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks the options that only make sense together.
+    fn validate(&self) -> Result<(), String> {
+        if self.history > self.max_history {
+            return Err(String::from("--history can't be more than --max-history"));
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let limits = self.limits.get_mut().unwrap();
+        match key {
+            "admins" => self
+                .admins
+                .get_mut()
+                .unwrap()
+                .extend(value.split(',').map(str::to_string)),
+            "ordered-results" => self.ordered_results = parse_value(key, value)?,
+            "websocket" => self.websocket = parse_value(key, value)?,
+            "broadcast-threshold" => self.broadcast_threshold = parse_value(key, value)?,
+            "join-notices" => self.join_notices = parse_value(key, value)?,
+            "say-mode" => self.say_mode = parse_value(key, value)?,
+            "unknown-commands" => self.unknown_commands = parse_value(key, value)?,
+            "max-message" => limits.max_message = parse_value(key, value)?,
+            "max-name" => limits.max_name = parse_value(key, value)?,
+            "max-channels" => limits.max_channels = parse_value(key, value)?,
+            "max-connections" => limits.max_connections = parse_value(key, value)?,
+            "rate" => limits.rate = parse_value(key, value)?,
+            "command-rate" => limits.command_rate = parse_value(key, value)?,
+            "command-strikes" => limits.command_strikes = parse_value(key, value)?,
+            "peer-queue" => {
+                self.peer_queue = parse_value(key, value)?;
+                if self.peer_queue == 0 {
+                    return Err(String::from("--peer-queue must be at least 1"));
+                }
+            }
+            "client-queue" => {
+                self.client_queue = parse_value(key, value)?;
+                if self.client_queue == 0 {
+                    return Err(String::from("--client-queue must be at least 1"));
+                }
+            }
+            "audit-log" => self.audit_log = Some(value.to_string()),
+            "message-log" => self.message_log = Some(value.to_string()),
+            "motd" => self.motd_file = Some(value.to_string()),
+            "replica-of" => self.replica_of = Some(parse_value(key, value)?),
+            "callback-timeout" => self.callback_timeout = parse_secs(key, value)?,
+            "max-pending" => {
+                self.max_pending = parse_value(key, value)?;
+                if self.max_pending == 0 {
+                    return Err(String::from("--max-pending must be at least 1"));
+                }
+            }
+            "history" => self.history = parse_value(key, value)?,
+            "max-history" => self.max_history = parse_value(key, value)?,
+            "history-max-age" => self.history_max_age = parse_period(key, value)?,
+            "account-ttl" => self.account_ttl = parse_period(key, value)?,
+            "sync-interval" => self.sync_interval = parse_period(key, value)?,
+            "first-line-timeout" => self.first_line_timeout = parse_secs(key, value)?,
+            "shutdown-grace" => self.shutdown_grace = parse_secs(key, value)?,
+            _ => return Err(format!("Unknown option --{}", key)),
+        }
+        Ok(())
+    }
+}
+
+/// A file every message said in our channels is appended to, as
+/// `<id> <unix time> <channel> <user> <message>`, with a reply's id followed by `/<parent id>`.
+/// Ids keep counting up from the file's last one, so they stay unique across restarts.
+///
+/// Lines are written by a thread of their own, which syncs however many were queued while it
+/// synced the last of them at once, so nobody saying a message waits on the disk but whoever
+/// waits for its ack.
+struct MessageLog {
+    /// The id the next message gets, held while its line is queued so lines are in id order
+    next_id: Mutex<u64>,
+    lines: std::sync::mpsc::Sender<(u64, String)>,
+    synced: watch::Receiver<Synced>,
+}
+
+/// How far the message log's writer has got.
+#[derive(Clone, Copy)]
+struct Synced {
+    /// The id of the latest message it's done with, whether or not it reached the disk
+    through: u64,
+    /// The id of the first message that didn't, after which none is trusted to have
+    failed_from: Option<u64>,
+}
+
+impl MessageLog {
+    fn open(path: &str) -> std::io::Result<Self> {
+        let next_id = match std::fs::read_to_string(path) {
+            Ok(contents) => contents
+                .lines()
+                .last()
+                .and_then(|line| line.split([' ', '/']).next()?.parse::<u64>().ok())
+                .map_or(1, |id| id + 1),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 1,
+            Err(e) => return Err(e),
+        };
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let (lines, queued) = std::sync::mpsc::channel();
+        let (written, synced) = watch::channel(Synced {
+            through: next_id - 1,
+            failed_from: None,
+        });
+        std::thread::spawn(move || write_log(file, queued, written));
+        Ok(MessageLog {
+            next_id: Mutex::new(next_id),
+            lines,
+            synced,
+        })
+    }
+
+    /// Queues the message to be written out, giving its id. `persisted` tells when it's on disk.
+    fn append(&self, channel: &str, user: &str, parent: Option<u64>, msg: &str) -> Option<u64> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut next_id = self.next_id.lock().unwrap();
+        let id = match parent {
+            Some(parent) => format!("{}/{}", next_id, parent),
+            None => next_id.to_string(),
+        };
+        let line = format!(
+            "{} {}.{:03} {} {} {}\n",
+            id,
+            now.as_secs(),
+            now.subsec_millis(),
+            channel,
+            user,
+            msg
+        );
+        // Only fails once the writer is gone, which it never goes while we hold `lines`
+        self.lines.send((*next_id, line)).ok()?;
+        *next_id += 1;
+        Some(*next_id - 1)
+    }
+
+    /// Waits for the message with this id to be written out, giving whether it reached the disk.
+    async fn persisted(&self, id: u64) -> bool {
+        let mut synced = self.synced.clone();
+        loop {
+            let now = *synced.borrow();
+            if now.through >= id {
+                return now.failed_from.is_none_or(|failed| failed > id);
+            }
+            if synced.changed().await.is_err() {
+                return false;
+            }
+        }
+    }
+
+    /// Whether a message was given this id.
+    fn issued(&self, id: u64) -> bool {
+        (1..*self.next_id.lock().unwrap()).contains(&id)
+    }
+}
+
+/// Writes the message log's lines as they're queued, until it's dropped.
+fn write_log(
+    mut file: std::fs::File,
+    queued: std::sync::mpsc::Receiver<(u64, String)>,
+    written: watch::Sender<Synced>,
+) {
+    let mut synced = *written.borrow();
+    while let Ok((first, mut batch)) = queued.recv() {
+        let mut through = first;
+        while let Ok((id, line)) = queued.try_recv() {
+            batch.push_str(&line);
+            through = id;
+        }
+        if let Err(e) = file
+            .write_all(batch.as_bytes())
+            .and_then(|_| file.sync_data())
+        {
+            eprintln!("Failed to write to the message log: {}", e);
+            synced.failed_from.get_or_insert(first);
+        }
+        synced.through = through;
+        let _ = written.send(synced);
+    }
+}
+
+/// Whether there's nothing to a message but whitespace. Nobody can be told such a message, so
+/// it isn't said.
+fn is_blank(msg: &str) -> bool {
+    msg.trim().is_empty()
+}
+
+/// What a member is told of a message, `RECVREPLY` with the parent's id when it's a reply.
+fn recv(from: &str, channel: &str, parent: Option<u64>, msg: &str) -> String {
+    match parent {
+        Some(parent) => format!("RECVREPLY {} {} {} {}\n", from, channel, parent, msg),
+        None => format!("RECV {} {} {}\n", from, channel, msg),
+    }
+}
+
+/// A report filed with `REPORT`, kept for admins to go through with `REPORTS`.
+struct Report {
+    reporter: Arc<String>,
+    channel: String,
+    user: String,
+    reason: String,
+}
+
+/// A file recording every account event on its own line, as
+/// `<unix time> <event> user=<name> addr=<peer> outcome=<ok|fail>`. Passwords never go in it.
+/// Like the message log it has a writer thread of its own, so nobody logging in waits on the disk.
+struct AuditLog {
+    lines: std::sync::mpsc::Sender<String>,
+}
+
+impl AuditLog {
+    fn open(path: &str) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let (lines, queued) = std::sync::mpsc::channel();
+        std::thread::spawn(move || write_audit(file, queued));
+        Ok(AuditLog { lines })
+    }
+
+    fn record(&self, event: &str, user: &str, addr: SocketAddr, ok: bool) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!(
+            "{}.{:03} {} user={} addr={} outcome={}\n",
+            now.as_secs(),
+            now.subsec_millis(),
+            event,
+            user,
+            addr,
+            if ok { "ok" } else { "fail" }
+        );
+        let _ = self.lines.send(line);
+    }
+}
+
+/// Writes out audit lines as they're queued, as many at a time as have built up.
+fn write_audit(mut file: std::fs::File, queued: std::sync::mpsc::Receiver<String>) {
+    while let Ok(mut batch) = queued.recv() {
+        while let Ok(line) = queued.try_recv() {
+            batch.push_str(&line);
+        }
+        // Losing an audit line shouldn't take anything else down with it
+        if let Err(e) = file.write_all(batch.as_bytes()) {
+            eprintln!("Failed to write to the audit log: {}", e);
+        }
+    }
+}
+
+/// What an `AuthBackend` answers with. Boxed, so which backend a server uses can be chosen while
+/// it runs.
+type AuthFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Where accounts and their passwords are kept, so they can live somewhere other than in memory.
+trait AuthBackend: Send + Sync {
+    /// Creates an account, failing if the name is taken. Checking and creating must be one step,
+    /// otherwise two registrations racing for one name could both succeed.
+    fn register<'a>(&'a self, username: &'a str, password: &'a str) -> AuthFuture<'a, bool>;
+    /// Whether the account exists and has this password
+    fn verify<'a>(&'a self, username: &'a str, password: &'a str) -> AuthFuture<'a, bool>;
+    fn exists<'a>(&'a self, username: &'a str) -> AuthFuture<'a, bool>;
+    /// Drops an account that expired. Backends whose accounts are managed elsewhere keep them.
+    fn remove(&self, _username: &str) {}
+}
+
+/// Accounts kept for as long as the server runs, which is what it does unless told otherwise.
+#[derive(Default)]
+struct InMemoryAuth {
+    users: RwLock<HashMap<String, String>>,
+}
+
+impl AuthBackend for InMemoryAuth {
+    fn register<'a>(&'a self, username: &'a str, password: &'a str) -> AuthFuture<'a, bool> {
+        use std::collections::hash_map::Entry;
+
+        let status = match self.users.write().unwrap().entry(username.to_string()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(password.to_string());
+                true
+            }
+        };
+        Box::pin(std::future::ready(status))
+    }
+
+    fn verify<'a>(&'a self, username: &'a str, password: &'a str) -> AuthFuture<'a, bool> {
+        let status = self
+            .users
+            .read()
+            .unwrap()
+            .get(username)
+            .is_some_and(|p| p == password);
+        Box::pin(std::future::ready(status))
+    }
+
+    fn exists<'a>(&'a self, username: &'a str) -> AuthFuture<'a, bool> {
+        let status = self.users.read().unwrap().contains_key(username);
+        Box::pin(std::future::ready(status))
+    }
+
+    fn remove(&self, username: &str) {
+        self.users.write().unwrap().remove(username);
+    }
+}
+
+struct Server {
+    config: Config,
+    addr: SocketAddr,
+    auth: Box<dyn AuthBackend>,
+    /// When each account last registered or logged in
+    last_seen: RwLock<HashMap<Arc<String>, Instant>>,
+    user_conns: RwLock<HashMap<Arc<String>, ClientChannel>>,
+    channels: RwLock<HashMap<String, RwLock<Channel>>>,
+    servers: RwLock<HashMap<SocketAddr, RemoteServer>>,
+    reports: RwLock<Vec<Report>>,
+    /// The senders each account does not want to receive messages from
+    ignores: RwLock<HashMap<Arc<String>, HashSet<String>>>,
+    client_connections: AtomicUsize,
+    server_connections: AtomicUsize,
+    /// The names shown for logged in users who chose one, as their latest session set it, for
+    /// member lists and keeping names from being taken twice
+    display_names: RwLock<HashMap<Arc<String>, String>>,
+    /// Messages that arrived for members while they were offline, oldest first
+    inboxes: RwLock<HashMap<Arc<String>, VecDeque<Arc<String>>>>,
+    /// Whatever clients want to keep with each account, set with `SET`
+    settings: RwLock<HashMap<Arc<String>, HashMap<String, String>>>,
+    /// Old channel names that now stand for another channel
+    aliases: RwLock<HashMap<String, String>>,
+    audit: Option<AuditLog>,
+    messages: Option<MessageLog>,
+    /// Every channel created, so peers' joins held waiting for one can go ahead
+    created: broadcast::Sender<String>,
+    /// Tells apart the answers to each `FEDPING` we send
+    next_ping: AtomicU64,
+    /// The message of the day, as one line
+    motd: RwLock<String>,
+}
+
+impl Server {
+    pub fn new(config: Config, addr: SocketAddr) -> Self {
+        let audit = config
+            .audit_log
+            .as_deref()
+            .map(|path| AuditLog::open(path).expect("Invalid audit log path"));
+        let messages = config
+            .message_log
+            .as_deref()
+            .map(|path| MessageLog::open(path).expect("Invalid message log path"));
+        Server {
+            config,
+            addr,
+            auth: Box::new(InMemoryAuth::default()),
+            last_seen: Default::default(),
+            user_conns: Default::default(),
+            channels: Default::default(),
+            servers: Default::default(),
+            reports: Default::default(),
+            ignores: Default::default(),
+            client_connections: Default::default(),
+            server_connections: Default::default(),
+            display_names: Default::default(),
+            inboxes: Default::default(),
+            settings: Default::default(),
+            aliases: Default::default(),
+            audit,
+            messages,
+            created: broadcast::channel(64).0,
+            next_ping: AtomicU64::new(1),
+            motd: Default::default(),
+        }
+    }
+
+    /// Reads the message of the day from `--motd` again. Its lines are joined with spaces, since
+    /// it's sent as the last field of a single line. A file that can't be read leaves it empty.
+    fn load_motd(&self) {
+        let Some(path) = &self.config.motd_file else {
+            return;
+        };
+        let motd = match std::fs::read_to_string(path) {
+            Ok(contents) => contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join(" "),
+            Err(e) => {
+                eprintln!("Failed to read the message of the day from {}: {}", path, e);
+                String::new()
+            }
+        };
+        *self.motd.write().unwrap() = motd;
+    }
+
+    fn connections(&self, kind: ConnectionKind) -> &AtomicUsize {
+        match kind {
+            ConnectionKind::Client => &self.client_connections,
+            ConnectionKind::Server => &self.server_connections,
+        }
+    }
+
+    /// The limits currently in force, which a reload may change.
+    fn limits(&self) -> Limits {
+        *self.config.limits.read().unwrap()
+    }
+}
+
+/// Counts a connection of one kind as open for as long as it's alive.
+struct Counted<'a>(&'a AtomicUsize);
+
+impl<'a> Counted<'a> {
+    /// Counts the connection, unless it's a client and `--max-connections` are already here.
+    fn admit(server: &'a Server, kind: ConnectionKind) -> Option<Self> {
+        let count = server.connections(kind);
+        let max = match kind {
+            ConnectionKind::Client => server.limits().max_connections,
+            ConnectionKind::Server => 0,
+        };
+        count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                within(n + 1, max).then_some(n + 1)
+            })
+            .ok()?;
+        Some(Counted(count))
+    }
+}
+
+impl Drop for Counted<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn audit(server: &Server, conn: &ClientConnection, event: &str, user: &str, ok: bool) {
+    if let Some(log) = &server.audit {
+        log.record(event, user, conn.peer_addr, ok);
+    }
+}
+
+async fn register(
+    server: &Server,
+    conn: &ClientConnection,
+    username: &str,
+    password: &str,
+) -> String {
+    async fn _register(server: &Server, username: &str, password: &str) -> bool {
+        if !within(username.len(), server.limits().max_name) {
+            return false;
+        }
+        // Nor may anyone take the name someone is shown as
+        if server
+            .display_names
+            .read()
+            .unwrap()
+            .values()
+            .any(|shown| shown == username)
+        {
+            return false;
+        }
+        // Saves a backend that creates accounts somewhere slow the trouble for names it has
+        if server.auth.exists(username).await || !server.auth.register(username, password).await {
+            return false;
+        }
+        server
+            .last_seen
+            .write()
+            .unwrap()
+            .insert(Arc::new(username.to_string()), Instant::now());
+        true
+    }
+
+    let status = _register(server, username, password).await;
+    audit(server, conn, "register", username, status);
+    format!("RESULT REGISTER {}\n", status as i8)
+}
+
+async fn login(
+    server: &Server,
+    conn: &mut ClientConnection,
+    username: &str,
+    password: &str,
+) -> String {
+    async fn _login(
+        server: &Server,
+        conn: &mut ClientConnection,
+        username: &str,
+        password: &str,
+    ) -> bool {
+        if !server.auth.verify(username, password).await {
+            return false;
+        }
+        let un = Arc::new(username.to_string());
+        // Whoever was logged in here before isn't any more, so nothing should reach them here
+        if let Some(previous) = conn.username.take().filter(|previous| *previous != un) {
+            log_out(server, &conn.channel, &previous);
+            conn.subscriptions.clear();
+        }
+        // A display name was only for the session of whoever set it
+        server.display_names.write().unwrap().remove(&un);
+        conn.display = None;
+        conn.username = Some(Arc::clone(&un));
+        server
+            .user_conns
+            .write()
+            .unwrap()
+            .insert(Arc::clone(&un), Arc::clone(&conn.channel));
+        server.last_seen.write().unwrap().insert(un, Instant::now());
+        true
+    }
+
+    let status = _login(server, conn, username, password).await;
+    audit(server, conn, "login", username, status);
+    format!("RESULT LOGIN {}\n", status as i8)
+}
+
+/// Removes the accounts nobody has logged in to for longer than `--account-ttl` as of `now`,
+/// along with everything kept for them, so their names can be registered again. Those online
+/// are kept however long ago they logged in.
+fn expire_accounts(server: &Server, now: Instant) {
+    let Some(ttl) = server.config.account_ttl else {
+        return;
+    };
+    let user_conns = server.user_conns.read().unwrap();
+    let mut last_seen = server.last_seen.write().unwrap();
+    let expired: Vec<Arc<String>> = last_seen
+        .iter()
+        .filter(|(user, seen)| now.duration_since(**seen) > ttl && !user_conns.contains_key(*user))
+        .map(|(user, _)| Arc::clone(user))
+        .collect();
+    if expired.is_empty() {
+        return;
+    }
+    for user in &expired {
+        server.auth.remove(user);
+        last_seen.remove(user);
+        server.settings.write().unwrap().remove(user);
+        server.ignores.write().unwrap().remove(user);
+        server.inboxes.write().unwrap().remove(user);
+        println!("Expired the account {}", user);
+    }
+    // Whoever takes a name next mustn't inherit the channels it owned
+    for c in server.channels.read().unwrap().values() {
+        let mut c = c.write().unwrap();
+        if c.owner
+            .as_ref()
+            .is_some_and(|owner| expired.contains(owner))
+        {
+            c.owner = None;
+        }
+    }
+}
+
+/// How a `JOIN` that didn't fail went.
+enum Joined {
+    /// Joined one of our channels, with the messages to replay to the user
+    Local(String),
+    /// Sent on to the peer hosting the channel, whose `FEDRESULT` answers the client
+    Pending,
+    /// The peer hosting the channel couldn't be asked, for the given reason
+    Failed(&'static str),
+}
+
+fn join(server: &Server, conn: &mut ClientConnection, channel: &str) -> Option<String> {
+    let channel = &canonical(server, channel);
+    let (status, replay) = match join_canonical(server, conn, channel) {
+        Some(Joined::Pending) => return None,
+        Some(Joined::Failed(reason)) => {
+            return Some(format!("RESULT JOIN {} 0 {}\n", channel, reason))
+        }
+        Some(Joined::Local(replay)) => (1, replay),
+        None => (0, String::new()),
+    };
+    Some(format!("RESULT JOIN {} {}\n{}", channel, status, replay))
+}
+
+/// Splits a channel on another server, `channel:addr`, into its name there and the server's
+/// address. Names with colons that aren't followed by an address are ours, and `CREATE` refuses
+/// names that would read as another server's.
+fn federated(channel: &str) -> Option<(&str, SocketAddr)> {
+    channel
+        .match_indices(':')
+        .find_map(|(i, _)| Some((&channel[..i], channel[i + 1..].parse().ok()?)))
+}
+
+/// Joins a channel already known by its canonical name.
+fn join_canonical(server: &Server, conn: &mut ClientConnection, channel: &str) -> Option<Joined> {
+    let username = conn.username.as_ref()?;
+
+    let channels = server.channels.read().unwrap();
+    match federated(channel) {
+        Some((channel, addr)) => {
+            if addr == server.addr {
+                return None;
+            }
+            let servers = server.servers.read().unwrap();
+            let remote = servers.get(&addr)?;
+            // Qualified the same way peers know us, so their answer finds the callback
+            let user = format!("{}@{}", username, server.addr);
+            let message = ServerRequest::Join {
+                user: &user,
+                channel,
+            }
+            .to_wire();
+            let message = ServerMessage::CallbackMessage {
+                channel: Arc::clone(&conn.channel),
+                user: Arc::new(user),
+                response: Response::Join {
+                    channel: channel.to_string(),
+                },
+                message,
+            };
+            if let Err(reason) = ask_peer(&remote.channel, message) {
+                if remote.channel.is_closed() {
+                    let dead = Arc::clone(&remote.channel);
+                    drop(servers);
+                    forget_closed(server, addr, &dead);
+                }
+                return Some(Joined::Failed(reason));
+            }
+            Some(Joined::Pending)
+        }
+        None => {
+            let c = channels.get(channel)?;
+            // read
+            {
+                let c = c.read().unwrap();
+                if c.users.contains_key(username) || !c.may_join(username, is_admin(server, conn)) {
+                    return None;
+                }
+            }
+            // write
+            {
+                let mut c = c.write().unwrap();
+                c.users
+                    .insert(Arc::clone(username), User::Local(Arc::clone(&conn.channel)));
+                conn.subscriptions.insert(channel, c.local.subscribe());
+            }
+            let user = format!("{}@{}", username, server.addr);
+            let c = c.read().unwrap();
+            announce_presence(&c, &user, channel, None);
+            c.notify(&server.config, "JOINED", username, channel);
+            Some(Joined::Local(c.replay(&server.config, Instant::now())))
+        }
+    }
+}
+
+/// Stops waiting on the answer to a federated join, which is answered as cancelled. The peer
+/// works through what it was sent in order, so may well have let them in by then; with no
+/// leaving a channel, cancelling only spares the client the wait.
+fn cancel_join(server: &Server, conn: &ClientConnection, channel: &str) -> Option<String> {
+    let refused = Some(format!("RESULT CANCEL JOIN {} 0\n", channel));
+    let (Some(username), Some((name, addr))) = (&conn.username, federated(channel)) else {
+        return refused;
+    };
+    let servers = server.servers.read().unwrap();
+    let Some(remote) = servers.get(&addr) else {
+        return refused;
+    };
+    // Only the link's own task may touch its callbacks, so it is asked to in turn
+    let message = ServerMessage::Cancel {
+        channel: Arc::clone(&conn.channel),
+        key: (
+            Arc::new(format!("{}@{}", username, server.addr)),
+            Response::Join {
+                channel: name.to_string(),
+            },
+        ),
+    };
+    match ask_peer(&remote.channel, message) {
+        Ok(()) => None,
+        Err(_) => refused,
+    }
+}
+
+/// Joins each of a comma separated list of channels, answering with every channel's status at
+/// once. A federated join's status is `pending`, and its own `RESULT JOIN` follows later.
+/// Messages replayed from the joined channels come after the summary.
+fn join_all(server: &Server, conn: &mut ClientConnection, channels: &str) -> String {
+    let channels: Vec<&str> = channels.split(',').collect();
+    if channels.len() > MAX_JOIN_ALL {
+        return String::from("RESULT JOINALL too-many\n");
+    }
+    let mut statuses = Vec::new();
+    let mut replays = String::new();
+    for channel in channels {
+        let status = match join_canonical(server, conn, &canonical(server, channel)) {
+            Some(Joined::Pending) => "pending",
+            Some(Joined::Failed(_)) => "0",
+            Some(Joined::Local(replay)) => {
+                replays.push_str(&replay);
+                "1"
+            }
+            None => "0",
+        };
+        statuses.push(format!("{}={}", channel, status));
+    }
+    format!("RESULT JOINALL {}\n{}", statuses.join(","), replays)
+}
+
+fn create(server: &Server, conn: &ClientConnection, channel: &str) -> String {
+    let limits = server.limits();
+    if !within(channel.len(), limits.max_name)
+        || federated(channel).is_some()
+        || server.aliases.read().unwrap().contains_key(channel)
+    {
+        return format!("RESULT CREATE {} 0\n", channel);
+    }
+    // read
+    {
+        let channels = server.channels.read().unwrap();
+        if channels.contains_key(channel) || !within(channels.len() + 1, limits.max_channels) {
+            // fail
+            return format!("RESULT CREATE {} 0\n", channel);
+        }
+    }
+    // write
+    {
+        server.channels.write().unwrap().insert(
+            channel.to_string(),
+            RwLock::new(Channel::new(conn.username.clone())),
+        );
+    }
+    // alert
+    {
+        let alert = Arc::new(ServerRequest::New { channel }.to_wire());
+        for remote in server.servers.read().unwrap().values() {
+            send_to_peer(&remote.channel, Arc::clone(&alert));
+        }
+    }
+    // Only once peers have `FEDNEW` queued, so they hear of the channel before any join to it
+    let _ = server.created.send(channel.to_string());
+    format!("RESULT CREATE {} 1\n", channel)
+}
+
+/// Says a message in one of our channels. Fails with `None`, and otherwise gives the message's
+/// id if it was queued for the message log.
+///
+/// Who to tell is worked out with the channel locked, but nobody is told, and nothing logged,
+/// until every lock is let go. Delivering must never wait while holding one, and it never waits
+/// on a member's queue either: were it to wait on a full one, two members talking to each other
+/// could each wait on the other's, which neither empties while it waits. Two messages said at
+/// once may reach members in either order, but one member's messages always arrive in the order
+/// they said them.
+fn _say(
+    server: &Server,
+    username: &String,
+    shown: &str,
+    channel_name: &str,
+    parent: Option<u64>,
+    msg: &str,
+) -> Option<Option<u64>> {
+    // Checked again here for peers, whose users' messages we haven't seen yet
+    if is_blank(msg) {
+        return None;
+    }
+    // Only ids we gave out can be replied to, and without the message log there are none
+    if let Some(parent) = parent {
+        if !server.messages.as_ref()?.issued(parent) {
+            return None;
+        }
+    }
+    let local_message = Arc::new(recv(shown, channel_name, parent, msg));
+    let (broadcast, recipients) = {
+        let channels = server.channels.read().unwrap();
+        let c = channels.get(channel_name)?.read().unwrap();
+        if !c.users.contains_key(username) {
+            return None;
+        }
+        c.messages.fetch_add(1, Ordering::Relaxed);
+        c.record(&server.config, &local_message, Instant::now());
+        // A large channel's connected members get the message through its broadcast instead
+        let local = c
+            .users
+            .values()
+            .filter(|u| matches!(u, User::Local(_)))
+            .count();
+        let threshold = server.config.broadcast_threshold;
+        let broadcast = threshold != 0 && local >= threshold;
+        if broadcast {
+            let _ = c.local.send(Arc::new(Broadcast {
+                from: username.clone(),
+                message: Arc::clone(&local_message),
+            }));
+        }
+        let ignores = server.ignores.read().unwrap();
+        let recipients: Vec<(Arc<String>, User)> = c
+            .users
+            .iter()
+            .filter(|(name, _)| !ignores.get(*name).is_some_and(|i| i.contains(username)))
+            .map(|(name, user)| (Arc::clone(name), user.clone()))
+            .collect();
+        (broadcast, recipients)
+    };
+    let id = server
+        .messages
+        .as_ref()
+        .and_then(|log| log.append(channel_name, username, parent, msg));
+
+    for (name, user) in &recipients {
+        match user {
+            User::Local(channel) => {
+                if !broadcast || channel.is_closed() {
+                    deliver(server, name, channel, Arc::clone(&local_message));
+                }
+                // Members of other servers are told by theirs, which knows what they want
+                if **name != *username
+                    && mentions(msg, name)
+                    && is_on(server, name, MENTIONS_SETTING)
+                {
+                    let notice = format!("MENTION {} {}\n", channel_name, shown);
+                    deliver(server, name, channel, Arc::new(notice));
+                }
+            }
+            User::Remote(channel) => {
+                let remote_message = Arc::new(
+                    ServerRequest::Recv {
+                        to_user: name,
+                        from_user: shown,
+                        channel: channel_name,
+                        parent,
+                        msg,
+                    }
+                    .to_wire(),
+                );
+                send_to_peer(channel, remote_message);
+            }
+        }
+    }
+    Some(id)
+}
+
+/// Says a message in a channel here or on a peer, in reply to the message with the id `parent`
+/// if there is one. With the message log, the message's id is only given once it's on disk.
+async fn say(
+    server: &Server,
+    conn: &mut ClientConnection,
+    channel: &str,
+    parent: Option<u64>,
+    msg: &str,
+) -> Option<String> {
+    let channel = &canonical(server, channel);
+    let Some(username) = conn.username.clone() else {
+        return Some(format!("RESULT SAY {} 0\n", channel));
+    };
+    let username = &username;
+
+    let limits = server.limits();
+    if !within(msg.len(), limits.max_message) {
+        return Some(format!("RESULT SAY {} 0 too-long\n", channel));
+    }
+    if is_blank(msg) {
+        return Some(format!("RESULT SAY {} 0 empty\n", channel));
+    }
+    if limits.rate != 0 {
+        let now = Instant::now();
+        while conn
+            .recent_says
+            .front()
+            .is_some_and(|&t| now.duration_since(t) >= Duration::from_secs(1))
+        {
+            conn.recent_says.pop_front();
+        }
+        if conn.recent_says.len() >= limits.rate {
+            return Some(format!("RESULT SAY {} 0 rate-limited\n", channel));
+        }
+        conn.recent_says.push_back(now);
+    }
+
+    // A channel on another server, which only takes messages from users who joined it
+    if let Some((name, addr)) = federated(channel) {
+        let servers = server.servers.read().unwrap();
+        let Some(remote) = servers.get(&addr) else {
+            return Some(format!("RESULT SAY {} 0\n", channel));
+        };
+        let user = format!("{}@{}", username, server.addr);
+        let members = remote.members.read().unwrap();
+        if !members.get(name).is_some_and(|m| m.contains(&user)) {
+            return Some(format!("RESULT SAY {} 0 not-joined\n", channel));
+        }
+        let message = ServerRequest::Say {
+            user: &user,
+            channel: name,
+            parent,
+            msg,
+        }
+        .to_wire();
+        let message = ServerMessage::CallbackMessage {
+            channel: Arc::clone(&conn.channel),
+            user: Arc::new(user),
+            response: Response::Say {
+                channel: name.to_string(),
+                message: msg.to_string(),
+            },
+            message,
+        };
+        return match ask_peer(&remote.channel, message) {
+            Ok(()) => None,
+            Err(reason) => {
+                if remote.channel.is_closed() {
+                    let dead = Arc::clone(&remote.channel);
+                    drop(members);
+                    drop(servers);
+                    forget_closed(server, addr, &dead);
+                }
+                Some(format!("RESULT SAY {} 0 {}\n", channel, reason))
+            }
+        };
+    }
+
+    let mode = server.config.say_mode;
+    if mode != SayMode::Member {
+        let is_member = server
+            .channels
+            .read()
+            .unwrap()
+            .get(channel)
+            .map(|c| c.read().unwrap().users.contains_key(username));
+        if is_member == Some(false) {
+            if mode == SayMode::Strict {
+                return Some(format!("RESULT SAY {} 0 not-in-channel\n", channel));
+            }
+            // Joined as a `JOIN` would, so whoever may not join the channel may not say in it
+            // either. There's nothing to replay, as they didn't ask to see what was said.
+            if join_canonical(server, conn, channel).is_none() {
+                return Some(format!("RESULT SAY {} 0\n", channel));
+            }
+        }
+    }
+
+    let shown = conn.display.as_deref().unwrap_or(username);
+    let Some(id) = _say(server, username, shown, channel, parent, msg) else {
+        return Some(format!("RESULT SAY {} 0\n", channel));
+    };
+    let persisted = match (id, &server.messages) {
+        (Some(id), Some(log)) => log.persisted(id).await.then_some(id),
+        _ => None,
+    };
+    let mut result = match persisted {
+        Some(id) => format!("RESULT SAY {} 1 id={}", channel, id),
+        None => format!("RESULT SAY {} 1", channel),
+    };
+    if is_on(server, username, ECHO_SETTING) {
+        result.push(' ');
+        result.push_str(msg);
+    }
+    result.push('\n');
+    Some(result)
+}
+
+/// Whether an account turned one of the settings the server acts on `on`.
+fn is_on(server: &Server, user: &str, setting: &str) -> bool {
+    server
+        .settings
+        .read()
+        .unwrap()
+        .get(&user.to_string())
+        .and_then(|s| s.get(setting))
+        .is_some_and(|v| v == "on")
+}
+
+/// Whether a message mentions a user as `@name`, rather than someone whose name starts the same.
+fn mentions(msg: &str, name: &str) -> bool {
+    msg.match_indices('@').any(|(i, _)| {
+        msg[i + 1..]
+            .strip_prefix(name)
+            .is_some_and(|after| !after.starts_with(|c: char| c.is_alphanumeric() || c == '_'))
+    })
+}
+
+/// Whether the connection is the one its user is in a channel of ours on.
+fn is_member(server: &Server, conn: &ClientConnection, channel: &str) -> bool {
+    let channels = server.channels.read().unwrap();
+    let (Some(username), Some(c)) = (&conn.username, channels.get(channel)) else {
+        return false;
+    };
+    let c = c.read().unwrap();
+    matches!(
+        c.users.get(username),
+        Some(User::Local(channel)) if Arc::ptr_eq(channel, &conn.channel)
+    )
+}
+
+fn ignores(server: &Server, conn: &ClientConnection, from: &str) -> bool {
+    conn.username.as_ref().is_some_and(|u| {
+        server
+            .ignores
+            .read()
+            .unwrap()
+            .get(u)
+            .is_some_and(|i| i.contains(from))
+    })
+}
+
+/// Hands a message to a local member. If the connection they joined on is gone it goes to the
+/// one they're logged in on now, or to their inbox while they're offline.
+fn deliver(server: &Server, name: &Arc<String>, channel: &ClientChannel, msg: Arc<String>) {
+    if channel
+        .send(ClientMessage::Message(Arc::clone(&msg)))
+        .is_ok()
+    {
+        return;
+    }
+    if let Some(current) = server.user_conns.read().unwrap().get(name) {
+        if current
+            .send(ClientMessage::Message(Arc::clone(&msg)))
+            .is_ok()
+        {
+            return;
+        }
+    }
+    let mut inboxes = server.inboxes.write().unwrap();
+    let inbox = inboxes.entry(Arc::clone(name)).or_default();
+    if inbox.len() == MAX_INBOX {
+        inbox.pop_front();
+    }
+    inbox.push_back(msg);
+}
+
+fn pause(conn: &mut ClientConnection) -> String {
+    let status = conn.paused.is_none();
+    conn.paused.get_or_insert_with(VecDeque::new);
+    format!("RESULT PAUSE {}\n", status as i8)
+}
+
+/// Lets pushed messages through again, starting with everything held back while paused.
+fn resume(conn: &mut ClientConnection) -> String {
+    let Some(held) = conn.paused.take() else {
+        return String::from("RESULT RESUME 0\n");
+    };
+    let mut s: String = held.iter().map(|m| m.as_str()).collect();
+    s.push_str("RESULT RESUME 1\n");
+    s
+}
+
+/// Writes a pushed message to the client, unless it's paused, in which case it's held. A
+/// redirect is written either way, and gives false as the connection ends with it.
+async fn push(conn: &mut ClientConnection, writer: &mut ClientOutput, msg: ClientMessage) -> bool {
+    let msg = match msg {
+        ClientMessage::Message(msg) => msg,
+        ClientMessage::Redirect(addr) => {
+            writer.write(&format!("{}{}\n", REDIRECT, addr)).await;
+            return false;
+        }
+        ClientMessage::Lagged => {
+            writer.write("RESULT ERROR lagged\n").await;
+            return false;
+        }
+        ClientMessage::Removed(channel) => {
+            conn.subscriptions.remove(&channel);
+            Arc::new(format!("REMOVED {}\n", channel))
+        }
+    };
+    match &mut conn.paused {
+        Some(held) => {
+            if held.len() == MAX_HELD {
+                held.pop_front();
+            }
+            held.push_back(msg);
+        }
+        None => writer.write(&msg).await,
+    }
+    true
+}
+
+/// Lets an admin hand a logged in user off to another server. They're sent
+/// `REDIRECT <addr>`, which compliant clients reconnect to, and their connection is closed.
+fn migrate(server: &Server, conn: &ClientConnection, user: &str, addr: &str) -> String {
+    let status = is_admin(server, conn)
+        && addr.parse::<SocketAddr>().is_ok()
+        && server
+            .user_conns
+            .read()
+            .unwrap()
+            .get(&user.to_string())
+            .is_some_and(|client| {
+                client
+                    .send(ClientMessage::Redirect(addr.to_string()))
+                    .is_ok()
+            });
+    format!("RESULT MIGRATE {} {} {}\n", user, addr, status as i8)
+}
+
+fn pending(server: &Server, conn: &ClientConnection) -> String {
+    let count = conn
+        .username
+        .as_ref()
+        .and_then(|u| server.inboxes.read().unwrap().get(u).map(VecDeque::len))
+        .unwrap_or(0);
+    format!("RESULT PENDING {}\n", count)
+}
+
+/// Writes out everything waiting in the user's inbox, followed by how many messages that was.
+fn fetch(server: &Server, conn: &ClientConnection) -> String {
+    let inbox = conn
+        .username
+        .as_ref()
+        .and_then(|u| server.inboxes.write().unwrap().remove(u))
+        .unwrap_or_default();
+    let mut s: String = inbox.iter().map(|m| m.as_str()).collect();
+    s.push_str(&format!("RESULT FETCH {}\n", inbox.len()));
+    s
+}
+
+/// Shows whether a user is online and how many listed channels they're in, if they made their
+/// profile public. Private profiles and unknown users look the same, so neither gives away that
+/// the account exists.
+fn profile(server: &Server, user: &str) -> String {
+    let name = user.to_string();
+    let public = server
+        .settings
+        .read()
+        .unwrap()
+        .get(&name)
+        .and_then(|s| s.get(PROFILE_SETTING))
+        .is_some_and(|v| v == "public");
+    if !public {
+        return format!("RESULT PROFILE {} 0\n", user);
+    }
+    let status = if server.user_conns.read().unwrap().contains_key(&name) {
+        "online"
+    } else {
+        "offline"
+    };
+    let channels = server
+        .channels
+        .read()
+        .unwrap()
+        .values()
+        .filter(|c| {
+            let c = c.read().unwrap();
+            c.visibility != Visibility::Hidden && c.users.contains_key(&name)
+        })
+        .count();
+    format!(
+        "RESULT PROFILE {} status={} channels={}\n",
+        user, status, channels
+    )
+}
+
+fn set(server: &Server, conn: &ClientConnection, key: &str, value: &str) -> String {
+    fn _set(server: &Server, conn: &ClientConnection, key: &str, value: &str) -> bool {
+        let Some(username) = conn.username.as_ref() else {
+            return false;
+        };
+        if key.len() > MAX_SETTING_KEY || value.len() > MAX_SETTING_VALUE {
+            return false;
+        }
+        let mut settings = server.settings.write().unwrap();
+        let settings = settings.entry(Arc::clone(username)).or_default();
+        if value.is_empty() {
+            settings.remove(key);
+        } else if settings.len() < MAX_SETTINGS || settings.contains_key(key) {
+            settings.insert(key.to_string(), value.to_string());
+        } else {
+            return false;
+        }
+        true
+    }
+
+    let status = _set(server, conn, key, value);
+    format!("RESULT SET {} {}\n", key, status as i8)
+}
+
+/// Answers with the setting's value, or just the key when it isn't set.
+fn get(server: &Server, conn: &ClientConnection, key: &str) -> String {
+    let settings = server.settings.read().unwrap();
+    match conn
+        .username
+        .as_ref()
+        .and_then(|u| settings.get(u)?.get(key))
+    {
+        Some(value) => format!("RESULT GET {} {}\n", key, value),
+        None => format!("RESULT GET {}\n", key),
+    }
+}
+
+/// Appends the channel list in its canonical form: a single leading space followed by the
+/// names sorted and separated by `", "`, or nothing at all when there are no channels.
+/// The line is always terminated with a newline.
+fn list_channels(server: &Server, s: &mut String) {
+    push_names(s, &listed_channels(server));
+}
+
+/// Our channels that anyone can see, in order.
+fn listed_channels(server: &Server) -> Vec<String> {
+    let channels = server.channels.read().unwrap();
+    let mut names: Vec<String> = channels
+        .iter()
+        .filter(|(_, c)| c.read().unwrap().visibility != Visibility::Hidden)
+        .map(|(name, _)| name.clone())
+        .collect();
+    names.sort_unstable();
+    names
+}
+
+fn push_names(s: &mut String, names: &[String]) {
+    if !names.is_empty() {
+        s.push(' ');
+        s.push_str(&names.join(", "));
+    }
+    s.push('\n');
+}
+
+/// Lists our channels, then every peer's as `channel:addr`, the name they're joined by. Peers
+/// can have channels named the same as ours or each other's, which the address tells apart.
+fn channels(server: &Server) -> String {
+    let mut names = listed_channels(server);
+    let mut federated: Vec<String> = Vec::new();
+    for (addr, remote) in server.servers.read().unwrap().iter() {
+        let channels = remote.channels.read().unwrap();
+        federated.extend(channels.iter().map(|c| format!("{}:{}", c, addr)));
+    }
+    federated.sort_unstable();
+    names.append(&mut federated);
+
+    let mut s = String::from("RESULT CHANNELS");
+    push_names(&mut s, &names);
+    s
+}
+
+fn report(
+    server: &Server,
+    conn: &mut ClientConnection,
+    channel: &str,
+    user: &str,
+    reason: &str,
+) -> String {
+    let Some(reporter) = conn.username.as_ref() else {
+        return String::from("RESULT REPORT 0\n");
+    };
+    if !server.channels.read().unwrap().contains_key(channel) {
+        return String::from("RESULT REPORT 0\n");
+    }
+
+    let notice = Arc::new(format!(
+        "RECV {} {} REPORT {} {} {} {}\n",
+        SYSTEM_USER, ADMIN_CHANNEL, reporter, channel, user, reason
+    ));
+    {
+        let user_conns = server.user_conns.read().unwrap();
+        for admin in server.config.admins.read().unwrap().iter() {
+            if let Some(admin_conn) = user_conns.get(admin) {
+                let _ = admin_conn.send(ClientMessage::Message(Arc::clone(&notice)));
+            }
+        }
+    }
+    server.reports.write().unwrap().push(Report {
+        reporter: Arc::clone(reporter),
+        channel: channel.to_string(),
+        user: user.to_string(),
+        reason: reason.to_string(),
+    });
+    String::from("RESULT REPORT 1\n")
+}
+
+/// Writes out every report filed so far for an admin, oldest first, followed by how many there
+/// were.
+fn reports(server: &Server, conn: &ClientConnection) -> String {
+    if !is_admin(server, conn) {
+        return String::from("RESULT REPORTS 0\n");
+    }
+    let reports = server.reports.read().unwrap();
+    let mut s = String::new();
+    for r in reports.iter() {
+        s.push_str(&format!(
+            "REPORT {} {} {} {}\n",
+            r.reporter, r.channel, r.user, r.reason
+        ));
+    }
+    s.push_str(&format!("RESULT REPORTS {}\n", reports.len()));
+    s
+}
+
+fn ignore(server: &Server, conn: &mut ClientConnection, user: &str) -> String {
+    let status = conn.username.as_ref().is_some_and(|un| {
+        server
+            .ignores
+            .write()
+            .unwrap()
+            .entry(Arc::clone(un))
+            .or_default()
+            .insert(account(server, user))
+    });
+    format!("RESULT IGNORE {} {}\n", user, status as i8)
+}
+
+fn unignore(server: &Server, conn: &mut ClientConnection, user: &str) -> String {
+    let status = conn.username.as_ref().is_some_and(|un| {
+        server
+            .ignores
+            .write()
+            .unwrap()
+            .get_mut(un)
+            .is_some_and(|i| i.remove(&account(server, user)))
+    });
+    format!("RESULT UNIGNORE {} {}\n", user, status as i8)
+}
+
+/// Lists the members of one of our channels or, for the `channel:addr` form, the members of a
+/// peer's channel as far as we have learnt them. Either list takes in the users of every server
+/// in the channel. With a page, only `count` members from `offset` on are listed, after the
+/// offset, how many were listed and how many there are in all.
+fn who(server: &Server, channel: &str, page: Option<(usize, usize)>) -> String {
+    let mut members: Vec<String> = match federated(channel) {
+        Some((name, addr)) => {
+            let servers = server.servers.read().unwrap();
+            let Some(remote) = servers.get(&addr) else {
+                return format!("RESULT WHO {} 0\n", channel);
+            };
+            let members = remote.members.read().unwrap();
+            members.get(name).into_iter().flatten().cloned().collect()
+        }
+        None => {
+            let channels = server.channels.read().unwrap();
+            let Some(c) = channels.get(channel) else {
+                return format!("RESULT WHO {} 0\n", channel);
+            };
+            let users = &c.read().unwrap().users;
+            let display_names = server.display_names.read().unwrap();
+            users
+                .keys()
+                .map(|name| display_names.get(name).unwrap_or(name).to_string())
+                .collect()
+        }
+    };
+    members.sort_unstable();
+    let Some((offset, count)) = page else {
+        return format!("RESULT WHO {} 1 {}\n", channel, members.join(", "));
+    };
+    let total = members.len();
+    let page: Vec<String> = members
+        .into_iter()
+        .skip(offset)
+        .take(count.min(MAX_WHO_PAGE))
+        .collect();
+    let mut msg = format!("RESULT WHO {} {} {} {}", channel, offset, page.len(), total);
+    if !page.is_empty() {
+        msg.push(' ');
+        msg.push_str(&page.join(", "));
+    }
+    msg.push('\n');
+    msg
+}
+
+/// Sets the name the user is shown as in messages and member lists for the rest of this
+/// session. Everything else, like routing and membership, still goes by the account name. No
+/// one may be shown as another account, or as a name someone else is shown as, so nobody can
+/// pass for anyone else; their own account name puts them back to being shown as that.
+async fn set_name(server: &Server, conn: &mut ClientConnection, display: &str) -> String {
+    let refused = format!("RESULT SETNAME {} 0\n", display);
+    let valid = display.len() <= MAX_DISPLAY_NAME && !display.contains([':', '@', ',']);
+    let Some(username) = conn.username.clone().filter(|_| valid) else {
+        return refused;
+    };
+    let own = username.as_str() == display;
+    if !own && server.auth.exists(display).await {
+        return refused;
+    }
+    {
+        let mut display_names = server.display_names.write().unwrap();
+        if display_names
+            .iter()
+            .any(|(user, shown)| *user != username && shown == display)
+        {
+            return refused;
+        }
+        if own {
+            display_names.remove(&username);
+        } else {
+            display_names.insert(Arc::clone(&username), display.to_string());
+        }
+    }
+    conn.display = (!own).then(|| display.to_string());
+    format!("RESULT SETNAME {} 1\n", display)
+}
+
+/// The account behind a name as members are shown it, which is the name itself unless it's
+/// someone's display name.
+fn account(server: &Server, shown: &str) -> String {
+    let display_names = server.display_names.read().unwrap();
+    display_names
+        .iter()
+        .find(|(_, name)| *name == shown)
+        .map_or(shown, |(user, _)| user.as_str())
+        .to_string()
+}
+
+/// The channel a name refers to, following an alias if there is one.
+fn canonical(server: &Server, channel: &str) -> String {
+    let aliases = server.aliases.read().unwrap();
+    aliases
+        .get(channel)
+        .map_or(channel, String::as_str)
+        .to_string()
+}
+
+/// Lets admins point an old channel name at an existing channel, so clients still using it
+/// end up in the new one.
+fn alias(server: &Server, conn: &ClientConnection, old: &str, new: &str) -> String {
+    fn _alias(server: &Server, conn: &ClientConnection, old: &str, new: &str) -> bool {
+        if !is_admin(server, conn) || federated(old).is_some() {
+            return false;
+        }
+        let channels = server.channels.read().unwrap();
+        let mut aliases = server.aliases.write().unwrap();
+        // Point straight at the channel so lookups never have to follow a chain
+        let new = aliases.get(new).cloned().unwrap_or_else(|| new.to_string());
+        if old == new || channels.contains_key(old) || !channels.contains_key(&new) {
+            return false;
+        }
+        aliases.insert(old.to_string(), new);
+        true
+    }
+
+    let status = _alias(server, conn, old, new);
+    format!("RESULT ALIAS {} {} {}\n", old, new, status as i8)
+}
+
+fn is_admin(server: &Server, conn: &ClientConnection) -> bool {
+    conn.username.as_ref().is_some_and(|username| {
+        let admins = server.config.admins.read().unwrap();
+        admins.contains(username.as_str())
+    })
+}
+
+/// Lists the peers we're federated with, by the address they listen on.
+fn servers(server: &Server) -> String {
+    let servers = server.servers.read().unwrap();
+    let mut addrs: Vec<String> = servers.keys().map(SocketAddr::to_string).collect();
+    addrs.sort_unstable();
+    let mut s = String::from("RESULT SERVERS");
+    if !addrs.is_empty() {
+        s.push(' ');
+        s.push_str(&addrs.join(", "));
+    }
+    s.push('\n');
+    s
+}
+
+/// Gives an admin a page of every channel we host with its members, one `CHANNEL` line each,
+/// then an `ONLINE` line of everyone logged in, for dashboards wanting the whole picture.
+fn snapshot(server: &Server, conn: &ClientConnection, offset: usize) -> String {
+    if !is_admin(server, conn) {
+        return String::from("RESULT SNAPSHOT 0\n");
+    }
+    let channels = server.channels.read().unwrap();
+    let mut names: Vec<&String> = channels.keys().collect();
+    names.sort_unstable();
+    let total = names.len();
+    let page: Vec<&String> = names
+        .into_iter()
+        .skip(offset)
+        .take(MAX_SNAPSHOT_PAGE)
+        .collect();
+
+    let mut s = format!("RESULT SNAPSHOT 1 {} {} {}\n", offset, page.len(), total);
+    for name in page {
+        let mut members: Vec<String> = channels[name]
+            .read()
+            .unwrap()
+            .users
+            .keys()
+            .map(|user| user.to_string())
+            .collect();
+        members.sort_unstable();
+        s.push_str("CHANNEL ");
+        s.push_str(name);
+        push_names(&mut s, &members);
+    }
+    let mut online: Vec<String> = server
+        .user_conns
+        .read()
+        .unwrap()
+        .keys()
+        .map(|user| user.to_string())
+        .collect();
+    online.sort_unstable();
+    s.push_str("ONLINE");
+    push_names(&mut s, &online);
+    s
+}
+
+/// Lets an admin cut a peer off. Its link sends it `FEDBYE` and closes, and everything learnt
+/// through it is forgotten as with any other closed link.
+fn defed(server: &Server, conn: &ClientConnection, addr: &str) -> String {
+    let status = is_admin(server, conn)
+        && addr.parse::<SocketAddr>().is_ok_and(|peer| {
+            let servers = server.servers.read().unwrap();
+            servers.get(&peer).map(|r| r.revoke.notify_one()).is_some()
+        });
+    format!("RESULT DEFED {} {}\n", addr, status as i8)
+}
+
+/// Describes one of our channels as `key=value` pairs after its status.
+/// Lets a channel's members, and admins, see how busy it is.
+fn chan_stats(server: &Server, conn: &ClientConnection, channel: &str) -> String {
+    let channels = server.channels.read().unwrap();
+    let Some(c) = channels.get(&canonical(server, channel)) else {
+        return format!("RESULT CHANSTATS {} 0\n", channel);
+    };
+    let c = c.read().unwrap();
+    let member = conn
+        .username
+        .as_ref()
+        .is_some_and(|u| c.users.contains_key(u));
+    if !member && !is_admin(server, conn) {
+        return format!("RESULT CHANSTATS {} 0\n", channel);
+    }
+    let created = c
+        .created
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    format!(
+        "RESULT CHANSTATS {} members={} messages={} created={}\n",
+        channel,
+        c.users.len(),
+        c.messages.load(Ordering::Relaxed),
+        created
+    )
+}
+
+/// Sends the message of the day, or a bare `RESULT MOTD` if there isn't one.
+fn motd(server: &Server) -> String {
+    let motd = server.motd.read().unwrap();
+    if motd.is_empty() {
+        String::from("RESULT MOTD\n")
+    } else {
+        format!("RESULT MOTD {}\n", motd)
+    }
+}
+
+fn info(server: &Server, channel: &str) -> String {
+    match describe(server, channel) {
+        Some(info) => format!("RESULT INFO {} 1 {}\n", channel, info),
+        None => format!("RESULT INFO {} 0\n", channel),
+    }
+}
+
+/// What `INFO` tells of one of our channels, as `key=value` pairs.
+fn describe(server: &Server, channel: &str) -> Option<String> {
+    let channels = server.channels.read().unwrap();
+    let c = channels.get(&canonical(server, channel))?.read().unwrap();
+    Some(format!(
+        "members={} history={}",
+        c.users.len(),
+        c.retention(&server.config)
+    ))
+}
+
+/// Answers `INFO` for each of a comma separated list of channels at once, with how many there
+/// are followed by each one's `RESULT INFO`. Channels we don't have are left out.
+fn info_all(server: &Server, channels: &str) -> String {
+    let channels: Vec<&str> = channels.split(',').collect();
+    if channels.len() > MAX_INFO_ALL {
+        return String::from("RESULT INFOALL too-many\n");
+    }
+    let infos: Vec<String> = channels
+        .into_iter()
+        .filter_map(|channel| {
+            let info = describe(server, channel)?;
+            Some(format!("RESULT INFO {} 1 {}\n", channel, info))
+        })
+        .collect();
+    format!("RESULT INFOALL {}\n{}", infos.len(), infos.concat())
+}
+
+/// Lists every mode `MODE` can set on a channel, as it is now: `+i` or `-i`, `+s` or `-s`, then
+/// those taking a value.
+fn modes(server: &Server, channel: &str) -> String {
+    let channels = server.channels.read().unwrap();
+    let Some(c) = channels.get(&canonical(server, channel)) else {
+        return format!("RESULT MODES {} 0\n", channel);
+    };
+    let c = c.read().unwrap();
+    let sign = |set| if set { '+' } else { '-' };
+    format!(
+        "RESULT MODES {} 1 {}i {}s quiet={} history={}\n",
+        channel,
+        sign(c.visibility == Visibility::Private),
+        sign(c.visibility == Visibility::Hidden),
+        if c.quiet { "on" } else { "off" },
+        c.retention(&server.config)
+    )
+}
+
+/// Lets a channel's owner, or an admin, change how the channel behaves. `history <n>` keeps the
+/// latest `n` messages for replay, up to `--max-history`. `+i` makes the channel private and
+/// `+s` hides it, while `-i` and `-s` undo them.
+fn set_mode(
+    server: &Server,
+    conn: &ClientConnection,
+    channel: &str,
+    mode: &str,
+    arg: Option<&str>,
+) -> String {
+    fn _set_mode(
+        server: &Server,
+        conn: &ClientConnection,
+        channel: &str,
+        mode: &str,
+        arg: Option<&str>,
+    ) -> bool {
+        let channels = server.channels.read().unwrap();
+        let Some(c) = channels.get(&canonical(server, channel)) else {
+            return false;
+        };
+        let mut c = c.write().unwrap();
+        let is_owner = conn.username.is_some() && c.owner == conn.username;
+        if !is_owner && !is_admin(server, conn) {
+            return false;
+        }
+        match (mode, arg) {
+            ("history", Some(n)) => {
+                let Ok(n) = n.parse::<usize>() else {
+                    return false;
+                };
+                if n > server.config.max_history {
+                    return false;
+                }
+                c.retention = Some(n);
+                let mut history = c.history.lock().unwrap();
+                while history.len() > n {
+                    history.pop_front();
+                }
+                true
+            }
+            ("quiet", Some("on" | "off")) => {
+                c.quiet = arg == Some("on");
+                true
+            }
+            ("+i", None) => {
+                c.visibility = Visibility::Private;
+                true
+            }
+            ("+s", None) => {
+                c.visibility = Visibility::Hidden;
+                true
+            }
+            ("-i", None) | ("-s", None) => {
+                let undone = if mode == "-i" {
+                    Visibility::Private
+                } else {
+                    Visibility::Hidden
+                };
+                if c.visibility == undone {
+                    c.visibility = Visibility::Public;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    let status = _set_mode(server, conn, channel, mode, arg);
+    match arg {
+        Some(arg) => format!(
+            "RESULT MODE {} {} {} {}\n",
+            channel, mode, arg, status as i8
+        ),
+        None => format!("RESULT MODE {} {} {}\n", channel, mode, status as i8),
+    }
+}
+
+/// Lets an admin time a round trip to a peer. The peer's `FEDPONG` or a timeout answers it.
+fn fed_ping_test(server: &Server, conn: &ClientConnection, addr: &str) -> Option<String> {
+    if !is_admin(server, conn) {
+        return Some(format!("RESULT FEDPINGTEST {} not-admin\n", addr));
+    }
+    let servers = server.servers.read().unwrap();
+    let Some(remote) = addr
+        .parse()
+        .ok()
+        .and_then(|peer: SocketAddr| servers.get(&peer))
+    else {
+        return Some(format!("RESULT FEDPINGTEST {} unknown-peer\n", addr));
+    };
+    let token = server.next_ping.fetch_add(1, Ordering::Relaxed).to_string();
+    let message = ServerRequest::Ping { token: &token }.to_wire();
+    let message = ServerMessage::CallbackMessage {
+        channel: Arc::clone(&conn.channel),
+        user: Arc::clone(conn.username.as_ref().unwrap()),
+        response: Response::Ping { token },
+        message,
+    };
+    match ask_peer(&remote.channel, message) {
+        Ok(()) => None,
+        Err(reason) => Some(format!("RESULT FEDPINGTEST {} {}\n", addr, reason)),
+    }
+}
+
+/// Lets a channel's owner, or an admin, take a member out of it. A member of ours is told with
+/// `REMOVED <channel>`, and every peer with members in the channel, the removed member's own
+/// included, hears of it through `FEDLEAVE`. As with `_say`, they're told once the channel's
+/// locks are let go.
+fn remove(server: &Server, conn: &ClientConnection, channel: &str, user: &str) -> String {
+    fn _remove(server: &Server, conn: &ClientConnection, channel: &str, user: &str) -> bool {
+        let channel = canonical(server, channel);
+        let (user, member, peers) = {
+            let channels = server.channels.read().unwrap();
+            let Some(c) = channels.get(&channel) else {
+                return false;
+            };
+            let mut c = c.write().unwrap();
+            let is_owner = conn.username.is_some() && c.owner == conn.username;
+            if !is_owner && !is_admin(server, conn) {
+                return false;
+            }
+            let Some((user, member)) = c.users.remove_entry(&String::from(user)) else {
+                return false;
+            };
+            c.notify(&server.config, "LEFT", &user, &channel);
+            let mut peers: Vec<ServerChannel> = Vec::new();
+            for member in std::iter::once(&member).chain(c.users.values()) {
+                let User::Remote(peer) = member else { continue };
+                if !peers.iter().any(|p| Arc::ptr_eq(p, peer)) {
+                    peers.push(Arc::clone(peer));
+                }
+            }
+            (user, member, peers)
+        };
+        if let User::Local(client) = &member {
+            // Otherwise they're elsewhere now, and weren't hearing it there
+            if client
+                .send(ClientMessage::Removed(channel.clone()))
+                .is_err()
+            {
+                let notice = Arc::new(format!("REMOVED {}\n", channel));
+                deliver(server, &user, client, notice);
+            }
+        }
+        let leave = Arc::new(
+            ServerRequest::Leave {
+                user: &user,
+                channel: &channel,
+            }
+            .to_wire(),
+        );
+        for peer in &peers {
+            send_to_peer(peer, Arc::clone(&leave));
+        }
+        true
+    }
+
+    let status = _remove(server, conn, channel, user);
+    format!("RESULT REMOVE {} {} {}\n", channel, user, status as i8)
+}
+
+/// Lets a channel's owner, or an admin, allow a user into the channel while it's private. Users
+/// of other servers are invited by their `user@addr` name.
+fn invite(server: &Server, conn: &ClientConnection, channel: &str, user: &str) -> String {
+    fn _invite(server: &Server, conn: &ClientConnection, channel: &str, user: &str) -> bool {
+        let channels = server.channels.read().unwrap();
+        let Some(c) = channels.get(&canonical(server, channel)) else {
+            return false;
+        };
+        let mut c = c.write().unwrap();
+        let is_owner = conn.username.is_some() && c.owner == conn.username;
+        if !is_owner && !is_admin(server, conn) {
+            return false;
+        }
+        c.invited.insert(user.to_string());
+        true
+    }
+
+    let status = _invite(server, conn, channel, user);
+    format!("RESULT INVITE {} {} {}\n", channel, user, status as i8)
+}
+
+/// How much of each thing there is a cap on is used up, and the cap, if there is one. Accounts
+/// have none, so the users counted are those online.
+fn capacity(server: &Server) -> String {
+    let limits = server.limits();
+    let cap = |max: usize| match max {
+        0 => String::from("unlimited"),
+        max => max.to_string(),
+    };
+    format!(
+        "RESULT CAPACITY connections={}/{} channels={}/{} users={}/unlimited\n",
+        server.client_connections.load(Ordering::SeqCst),
+        cap(limits.max_connections),
+        server.channels.read().unwrap().len(),
+        cap(limits.max_channels),
+        server.user_conns.read().unwrap().len()
+    )
+}
+
+fn limits(server: &Server) -> String {
+    let limits = server.limits();
+    format!(
+        "RESULT LIMITS maxmsg={} maxname={} maxchannels={} rate={}\n",
+        limits.max_message, limits.max_name, limits.max_channels, limits.rate
+    )
+}
+
+/// Makes a link that finished its handshake the way we reach its peer. Two servers that list each
+/// other both dial, which would leave the pair federated twice over, so only the link dialed by
+/// the lower of the two addresses is kept. Both ends come to the same answer on their own: a
+/// losing link is refused, and a winning one takes over from the other, which is sent `FEDBYE`.
+/// A second link from the same direction is the peer reconnecting before we noticed it left, and
+/// replaces the old one.
+fn register_peer(server: &Server, conn: &ServerConnection, outbound: bool) -> bool {
+    let mut servers = server.servers.write().unwrap();
+    if let Some(existing) = servers.get(&conn.server_addr) {
+        if existing.outbound != outbound {
+            let (dialer, dialed) = if outbound {
+                (server.addr, conn.server_addr)
+            } else {
+                (conn.server_addr, server.addr)
+            };
+            if dialer > dialed {
+                return false;
+            }
+            existing.revoke.notify_one();
+        }
+    }
+    servers.insert(
         conn.server_addr,
         RemoteServer {
             channel: Arc::clone(&conn.channel),
+            revoke: Arc::clone(&conn.revoke),
+            outbound,
             channels: Default::default(),
+            members: Default::default(),
         },
     );
+    true
+}
+
+fn fed_out(server: &Server, conn: &mut ServerConnection, addr: Option<&str>) -> Option<String> {
+    // Peers announce the address they listen on, which is what our users and the rest of the
+    // mesh know them by. Without one we can only go by this end of the connection. Only an
+    // address on the host it's connecting from is taken, so nobody can pass for another peer
+    // and have that peer's own link revoked.
+    if let Some(addr) = addr {
+        let addr = addr.parse::<SocketAddr>();
+        let Some(addr) = addr.ok().filter(|a| a.ip() == conn.peer_addr.ip()) else {
+            conn.handshake = Handshake::Rejected;
+            return Some(
+                ServerRequest::Reject {
+                    reason: "bad-address",
+                }
+                .to_wire(),
+            );
+        };
+        if addr == server.addr {
+            conn.handshake = Handshake::Rejected;
+            return Some(ServerRequest::Reject { reason: "self" }.to_wire());
+        }
+        conn.server_addr = addr;
+    }
+    if !register_peer(server, conn, false) {
+        conn.handshake = Handshake::Rejected;
+        return Some(
+            ServerRequest::Reject {
+                reason: "duplicate",
+            }
+            .to_wire(),
+        );
+    }
+    Some(ServerRequest::Confirm.to_wire())
+}
+
+fn fed_confirm(server: &Server, conn: &mut ServerConnection) -> Option<String> {
+    if !register_peer(server, conn, true) {
+        conn.handshake = Handshake::Rejected;
+        return Some(
+            ServerRequest::Reject {
+                reason: "duplicate",
+            }
+            .to_wire(),
+        );
+    }
     let mut s = String::from("FEDCHANNELS");
     list_channels(server, &mut s);
     Some(s)
@@ -450,104 +3012,292 @@ fn fed_confirm(server: &Server, conn: &mut ServerConnection) -> Option<String> {
 
 fn fed_channels(server: &Server, conn: &mut ServerConnection, channels: &str) -> Option<String> {
     let servers = server.servers.read().unwrap();
-    let remote = if let Some(r) = servers.get(&conn.server_addr) {
-        r
-    } else {
-        panic!();
-    };
+    // Forgotten by a DEFED, or replaced by a newer link, while this was on its way
+    let remote = servers.get(&conn.server_addr)?;
 
-    for channel in channels.split(", ") {
+    for channel in channels.split(", ").filter(|c| !c.is_empty()) {
         remote.channels.write().unwrap().insert(channel.to_string());
     }
     None
 }
 
+/// Replaces what we know of the peer's channels with its full list, dropping any we missed the
+/// removal of and adding any we missed the creation of.
+fn fed_sync(server: &Server, conn: &mut ServerConnection, channels: &str) -> Option<String> {
+    let servers = server.servers.read().unwrap();
+    let remote = servers.get(&conn.server_addr)?;
+
+    *remote.channels.write().unwrap() = channels
+        .split(", ")
+        .filter(|c| !c.is_empty())
+        .map(str::to_string)
+        .collect();
+    None
+}
+
 fn fed_new(server: &Server, conn: &mut ServerConnection, channel: &str) -> Option<String> {
     let servers = server.servers.read().unwrap();
-    let remote = if let Some(r) = servers.get(&conn.server_addr) {
-        r
-    } else {
-        panic!();
-    };
+    let remote = servers.get(&conn.server_addr)?;
 
     remote.channels.write().unwrap().insert(channel.to_string());
     None
 }
 
+/// The name a member of one of our channels is known by on other servers.
+fn qualified(server: &Server, name: &str, user: &User) -> String {
+    match user {
+        User::Local(_) => format!("{}@{}", name, server.addr),
+        User::Remote(_) => name.to_string(),
+    }
+}
+
+/// Memberships of a channel live on the server that hosts it. Whenever someone joins, each
+/// peer with members in the channel is sent a `FEDPRESENCE <user> <channel>` so its view of the
+/// channel stays accurate, except for `from`, the peer the joining user came through. A peer
+/// whose user is joining for the first time is instead sent every existing member.
+fn announce_presence(c: &Channel, user: &str, channel: &str, from: Option<&ServerChannel>) {
+    let message = Arc::new(ServerRequest::Presence { user, channel }.to_wire());
+    let mut notified: Vec<&ServerChannel> = Vec::new();
+    for member in c.users.values() {
+        let User::Remote(peer) = member else { continue };
+        if from.is_some_and(|f| Arc::ptr_eq(f, peer))
+            || notified.iter().any(|n| Arc::ptr_eq(n, peer))
+        {
+            continue;
+        }
+        send_to_peer(peer, Arc::clone(&message));
+        notified.push(peer);
+    }
+}
+
+fn fed_presence(
+    server: &Server,
+    conn: &mut ServerConnection,
+    user: &str,
+    channel: &str,
+) -> Option<String> {
+    let servers = server.servers.read().unwrap();
+    let remote = servers.get(&conn.server_addr)?;
+
+    remote
+        .members
+        .write()
+        .unwrap()
+        .entry(channel.to_string())
+        .or_default()
+        .insert(user.to_string());
+    None
+}
+
+/// Forgets a member of one of the peer's channels. If they're one of our users, they're told
+/// they were removed.
+fn fed_leave(
+    server: &Server,
+    conn: &ServerConnection,
+    user: &str,
+    channel: &str,
+) -> Option<String> {
+    if let Some(remote) = server.servers.read().unwrap().get(&conn.server_addr) {
+        if let Some(members) = remote.members.write().unwrap().get_mut(channel) {
+            members.remove(user);
+        }
+    }
+    // The peer knows our users by the name they joined with, `user@addr`
+    let name = user.strip_suffix(&format!("@{}", server.addr))?;
+    if let Some(client) = server.user_conns.read().unwrap().get(&name.to_string()) {
+        let notice = format!("REMOVED {}:{}\n", channel, conn.server_addr);
+        let _ = client.send(ClientMessage::Message(Arc::new(notice)));
+    }
+    None
+}
+
+/// Joins a peer's user to one of our channels. A join to a channel we don't have is held for a
+/// moment rather than refused, as it may have crossed the channel's `FEDNEW` on the way.
 fn fed_join(
     server: &Server,
     conn: &mut ServerConnection,
     user: &str,
     channel: &str,
 ) -> Option<String> {
+    if !server.channels.read().unwrap().contains_key(channel) {
+        if !conn
+            .held_joins
+            .iter()
+            .any(|held| held.user == user && held.channel == channel)
+        {
+            conn.held_joins.push(HeldJoin {
+                deadline: Instant::now() + JOIN_GRACE,
+                user: user.to_string(),
+                channel: channel.to_string(),
+            });
+        }
+        return None;
+    }
+    Some(answer_join(server, conn, user, channel))
+}
+
+fn answer_join(server: &Server, conn: &mut ServerConnection, user: &str, channel: &str) -> String {
     fn _join(server: &Server, conn: &mut ServerConnection, user: &str, channel: &str) -> bool {
         let channels = server.channels.read().unwrap();
         let Some(c) = channels.get(channel) else { return false };
         let user = user.to_string();
-        // read
-        {
-            if c.read().unwrap().users.contains_key(&user) {
-                return false;
-            }
+        if !c.read().unwrap().may_join(&user, false) {
+            return false;
         }
         // write
         {
-            c.write()
-                .unwrap()
-                .users
-                .insert(Arc::new(user), User::Remote(Arc::clone(&conn.channel)));
+            let mut c = c.write().unwrap();
+            match c.users.get_mut(&user) {
+                // Already joined is still joined. The peer may have come back on a new link,
+                // so deliveries follow it there. Everyone else already knows about them.
+                Some(User::Remote(peer)) => *peer = Arc::clone(&conn.channel),
+                Some(User::Local(_)) => return false,
+                None => {
+                    c.users.insert(
+                        Arc::new(user.clone()),
+                        User::Remote(Arc::clone(&conn.channel)),
+                    );
+                    announce_presence(&c, &user, channel, Some(&conn.channel));
+                    c.notify(&server.config, "JOINED", &user, channel);
+                }
+            }
+        }
+        let c = c.read().unwrap();
+        // Catch the peer up on everyone who was here before its user
+        for (name, member) in &c.users {
+            if **name == user {
+                continue;
+            }
+            let user = qualified(server, name, member);
+            let message = ServerRequest::Presence {
+                user: &user,
+                channel,
+            }
+            .to_wire();
+            send_to_peer(&conn.channel, Arc::new(message));
         }
         true
     }
 
     let status = _join(server, conn, user, channel);
-    Some(format!(
-        "FEDRESULT {} JOIN {} {}\n",
-        user, channel, status as i8
-    ))
+    let status = if status { "1" } else { "0" };
+    ServerRequest::Result(ServerResult::Join {
+        user,
+        channel,
+        status,
+    })
+    .to_wire()
 }
 
-fn fed_say(server: &Server, user: &str, channel: &str, msg: &str) -> Option<String> {
-    let status = _say(server, &user.to_string(), user, channel);
-    Some(format!(
-        "FEDRESULT {} SAY {} {} {}\n",
-        user, channel, status as i8, msg
-    ))
+fn fed_say(
+    server: &Server,
+    user: &str,
+    channel: &str,
+    parent: Option<u64>,
+    msg: &str,
+) -> Option<String> {
+    let status = _say(server, &user.to_string(), user, channel, parent, msg).is_some();
+    let status = if status { "1" } else { "0" };
+    Some(
+        ServerRequest::Result(ServerResult::Say {
+            user,
+            channel,
+            status,
+            msg,
+        })
+        .to_wire(),
+    )
 }
 
 fn fed_recv(
     server: &Server,
+    conn: &ServerConnection,
     to_user: &str,
     from_user: &str,
     channel: &str,
+    parent: Option<u64>,
     msg: &str,
 ) -> Option<String> {
-    if let Some(conn) = server.user_conns.read().unwrap().get(&to_user.to_string()) {
-        conn.send(Arc::new(format!(
-            "RECV {} {} {}\n",
-            from_user, channel, msg
-        )))
-        .unwrap()
+    // The peer knows our users by the name they joined with, `user@addr`
+    let to_user = to_user.split_once('@').map_or(to_user, |(name, _)| name);
+    if let Some(client) = server.user_conns.read().unwrap().get(&to_user.to_string()) {
+        let channel_name = format!("{}:{}", channel, conn.server_addr);
+        let _ = client.send(ClientMessage::Message(Arc::new(recv(
+            from_user,
+            &channel_name,
+            parent,
+            msg,
+        ))));
+        let own = format!("{}@{}", to_user, server.addr);
+        if from_user != own && mentions(msg, to_user) && is_on(server, to_user, MENTIONS_SETTING) {
+            let notice = format!("MENTION {}:{} {}\n", channel, conn.server_addr, from_user);
+            let _ = client.send(ClientMessage::Message(Arc::new(notice)));
+        }
     }
 
     None
 }
 
-fn fed_result_join(conn: &mut ServerConnection, user: &str, channel: &str, status: &str) {
+fn fed_result_join(
+    server: &Server,
+    conn: &mut ServerConnection,
+    user: &str,
+    channel: &str,
+    status: &str,
+) {
     let key = (
         Arc::new(user.to_string()),
         Response::Join {
             channel: channel.to_string(),
         },
     );
-    if let Some(sender) = conn.callbacks.remove(&key) {
-        sender
-            .send(Arc::new(format!("RESULT JOIN {} {}\n", channel, status)))
-            .unwrap();
+    let Some(callback) = conn.callbacks.remove(&key) else {
+        eprintln!("{} answered a join nobody is waiting on", conn.server_addr);
+        return;
+    };
+    // Remember the user made it in, so they're allowed to talk there
+    if status == "1" {
+        if let Some(remote) = server.servers.read().unwrap().get(&conn.server_addr) {
+            remote
+                .members
+                .write()
+                .unwrap()
+                .entry(channel.to_string())
+                .or_default()
+                .insert(user.to_string());
+        }
     }
+    let message = format!("RESULT JOIN {}:{} {}\n", channel, conn.server_addr, status);
+    let _ = callback
+        .channel
+        .send(ClientMessage::Message(Arc::new(message)));
 }
 
-fn fed_result_say(conn: &mut ServerConnection, user: &str, channel: &str, status: &str, msg: &str) {
+/// Answers the admin waiting on the ping with how long the round trip took.
+fn fed_pong(conn: &mut ServerConnection, token: &str) {
+    let response = Response::Ping {
+        token: token.to_string(),
+    };
+    // Tokens are unique, whoever asked
+    let Some(key) = conn.callbacks.keys().find(|(_, r)| *r == response).cloned() else {
+        return;
+    };
+    let callback = conn.callbacks.remove(&key).unwrap();
+    let rtt = callback.sent.elapsed().as_millis();
+    let message = format!("RESULT FEDPINGTEST {} {}\n", conn.server_addr, rtt);
+    let _ = callback
+        .channel
+        .send(ClientMessage::Message(Arc::new(message)));
+}
+
+fn fed_result_say(
+    server: &Server,
+    conn: &mut ServerConnection,
+    user: &str,
+    channel: &str,
+    status: &str,
+    msg: &str,
+) {
     let key = (
         Arc::new(user.to_string()),
         Response::Say {
@@ -555,234 +3305,1515 @@ fn fed_result_say(conn: &mut ServerConnection, user: &str, channel: &str, status
             message: msg.to_string(),
         },
     );
-    if let Some(sender) = conn.callbacks.remove(&key) {
-        sender
-            .send(Arc::new(format!(
-                "RESULT SAY {} {} {}\n",
-                channel, msg, status
-            )))
-            .unwrap();
+    let Some(callback) = conn.callbacks.remove(&key) else {
+        eprintln!("{} answered a say nobody is waiting on", conn.server_addr);
+        return;
+    };
+    let mut result = format!("RESULT SAY {}:{} {}", channel, conn.server_addr, status);
+    // The peer knows our users by the name they said it with, `user@addr`
+    let name = user.strip_suffix(&format!("@{}", server.addr));
+    if status == "1" && name.is_some_and(|name| is_on(server, name, ECHO_SETTING)) {
+        result.push(' ');
+        result.push_str(msg);
     }
+    result.push('\n');
+    // The client may have gone while waiting
+    let _ = callback
+        .channel
+        .send(ClientMessage::Message(Arc::new(result)));
 }
 
-async fn process_server_request(
+/// Handles one line from a peer, returning the reply. The reply isn't written straight away:
+/// whatever handling it queued for the peer has to go out before it.
+fn process_server_request(
     server: &Server,
     conn: &mut ServerConnection,
-    writer: &mut OwnedWriteHalf,
     req: ServerRequest<'_>,
-) {
-    let msg = match req {
-        ServerRequest::Out => fed_out(server, conn),
+) -> Option<String> {
+    match (conn.handshake, &req) {
+        (handshake, ServerRequest::Bye) => {
+            conn.handshake = Handshake::Closed;
+            // Answered in kind when it ends the federation, so the peer hears it was heard rather
+            // than waiting to see the connection drop. A link that isn't federated, or already
+            // gave way to another, just closes.
+            let current = matches!(handshake, Handshake::Established)
+                && server
+                    .servers
+                    .read()
+                    .unwrap()
+                    .get(&conn.server_addr)
+                    .is_some_and(|r| Arc::ptr_eq(&r.channel, &conn.channel));
+            return current.then(|| ServerRequest::Bye.to_wire());
+        }
+        (Handshake::Established, _) => {}
+        (Handshake::AwaitingOut, ServerRequest::Out { .. }) => {
+            conn.handshake = Handshake::AwaitingChannels
+        }
+        (_, ServerRequest::Reject { reason }) => {
+            eprintln!("{} refused to federate: {}", conn.server_addr, reason);
+            conn.handshake = Handshake::Rejected;
+            return None;
+        }
+        (Handshake::AwaitingConfirm, ServerRequest::Confirm)
+        | (Handshake::AwaitingChannels, ServerRequest::Channels { .. }) => {
+            conn.handshake = Handshake::Established
+        }
+        // A channel created while the peer was confirming can be announced ahead of its list
+        (Handshake::AwaitingChannels, ServerRequest::New { .. }) => {}
+        _ => return Some(String::from("FEDERROR handshake-incomplete\n")),
+    }
+
+    match req {
+        ServerRequest::Out { addr } => fed_out(server, conn, addr),
+        // Handled with the rest of the handshake
+        ServerRequest::Reject { .. } | ServerRequest::Bye => None,
         ServerRequest::Confirm => fed_confirm(server, conn),
         ServerRequest::Channels { channels } => fed_channels(server, conn, channels),
+        ServerRequest::Sync { channels } => fed_sync(server, conn, channels),
         ServerRequest::New { channel } => fed_new(server, conn, channel),
         ServerRequest::Join { user, channel } => fed_join(server, conn, user, channel),
-        ServerRequest::Say { user, channel, msg } => fed_say(server, user, channel, msg),
+        ServerRequest::Presence { user, channel } => fed_presence(server, conn, user, channel),
+        ServerRequest::Leave { user, channel } => fed_leave(server, conn, user, channel),
+        ServerRequest::Ping { token } => Some(ServerRequest::Pong { token }.to_wire()),
+        ServerRequest::Pong { token } => {
+            fed_pong(conn, token);
+            None
+        }
+        ServerRequest::Say {
+            user,
+            channel,
+            parent,
+            msg,
+        } => fed_say(server, user, channel, parent, msg),
         ServerRequest::Recv {
             to_user,
             from_user,
             channel,
+            parent,
             msg,
-        } => fed_recv(server, to_user, from_user, channel, msg),
+        } => fed_recv(server, conn, to_user, from_user, channel, parent, msg),
         ServerRequest::Result(res) => {
             match res {
                 ServerResult::Join {
                     user,
                     channel,
                     status,
-                } => fed_result_join(conn, user, channel, status),
+                } => fed_result_join(server, conn, user, channel, status),
                 ServerResult::Say {
                     user,
                     channel,
                     status,
                     msg,
-                } => fed_result_say(conn, user, channel, status, msg),
+                } => fed_result_say(server, conn, user, channel, status, msg),
             }
             None
         }
-    };
-    if let Some(msg) = msg {
-        writer.write_all(msg.as_bytes()).await.unwrap();
     }
 }
 
-async fn process_server(
+/// Writes a message from the link's queue to the peer. As with clients, a peer that has gone
+/// away is noticed on the read side, so failed writes are ignored.
+async fn forward(
+    server: &Server,
+    conn: &mut ServerConnection,
+    writer: &mut OwnedWriteHalf,
+    msg: ServerMessage,
+) {
+    match msg {
+        ServerMessage::Message(msg) => {
+            let _ = writer.write_all(msg.as_bytes()).await;
+        }
+        ServerMessage::CallbackMessage {
+            channel,
+            user,
+            response,
+            message,
+        } => {
+            let key = (user, response);
+            // A join retried while the first attempt is outstanding waits on its answer rather
+            // than asking the peer twice
+            if let (Response::Join { .. }, Some(callback)) = (&key.1, conn.callbacks.get_mut(&key))
+            {
+                callback.channel = channel;
+                return;
+            }
+            // A peer that never answers can't have us remember requests without end. Those
+            // already waiting still time out, making room again.
+            if conn.callbacks.len() >= server.config.max_pending {
+                let message = callback_failure(&key.1, conn.server_addr, "too-many-pending");
+                let _ = channel.send(ClientMessage::Message(Arc::new(message)));
+                return;
+            }
+            let _ = writer.write_all(message.as_bytes()).await;
+            let sent = Instant::now();
+            let deadline = sent + server.config.callback_timeout;
+            conn.expiries.push_back((deadline, key.clone()));
+            conn.callbacks.insert(
+                key,
+                Callback {
+                    channel,
+                    sent,
+                    deadline,
+                },
+            );
+        }
+        ServerMessage::Cancel { channel, key } => {
+            let message = match conn.callbacks.remove(&key) {
+                // Its expiry is skipped once it finds the callback gone
+                Some(_) => callback_failure(&key.1, conn.server_addr, "cancelled"),
+                None => match &key.1 {
+                    Response::Join { channel } => {
+                        format!("RESULT CANCEL JOIN {}:{} 0\n", channel, conn.server_addr)
+                    }
+                    _ => return,
+                },
+            };
+            let _ = channel.send(ClientMessage::Message(Arc::new(message)));
+        }
+    }
+}
+
+/// Everything bound for a peer leaves in the order it was queued, whichever task queued it,
+/// so replies wait behind whatever is already queued rather than overtaking it.
+async fn reply(
     server: &Server,
-    mut lines: Lines<BufReader<OwnedReadHalf>>,
+    conn: &mut ServerConnection,
+    writer: &mut OwnedWriteHalf,
+    receiver: &mut mpsc::Receiver<ServerMessage>,
+    reply: Option<String>,
+) {
+    while let Ok(msg) = receiver.try_recv() {
+        forward(server, conn, writer, msg).await;
+    }
+    if let Some(reply) = reply {
+        let _ = writer.write_all(reply.as_bytes()).await;
+    }
+}
+
+async fn process_server(
+    server: &Arc<Server>,
+    mut lines: LineReader,
     mut writer: OwnedWriteHalf,
     mut shutdown: Shutdown,
     inital_request: ServerRequest<'_>,
+    peer_addr: SocketAddr,
+    outbound: bool,
 ) {
-    let addr = lines.get_ref().get_ref().local_addr().unwrap();
-    let (sender, mut receiver) = mpsc::unbounded_channel::<ServerMessage>();
+    // We know who we dialed, but who dialed us is only known once they send `FEDOUT`
+    let addr = if outbound {
+        peer_addr
+    } else {
+        match lines.get_ref().local_addr() {
+            Ok(addr) => addr,
+            // Reset before we could ask, so there's nobody left to federate with
+            Err(e) => return eprintln!("Lost a peer from {} as it connected: {}", peer_addr, e),
+        }
+    };
+    let (queue, mut receiver) = PeerQueue::new(server.config.peer_queue);
 
     let mut connection = ServerConnection {
-        channel: Arc::new(sender),
+        channel: Arc::new(queue),
         server_addr: addr,
+        peer_addr,
         callbacks: Default::default(),
+        expiries: Default::default(),
+        held_joins: Default::default(),
+        revoke: Default::default(),
+        handshake: if outbound {
+            Handshake::AwaitingConfirm
+        } else {
+            Handshake::AwaitingOut
+        },
     };
 
-    process_server_request(server, &mut connection, &mut writer, inital_request).await;
+    let msg = process_server_request(server, &mut connection, inital_request);
+    reply(server, &mut connection, &mut writer, &mut receiver, msg).await;
+    if connection.handshake.closing() {
+        forget_peer(server, &mut connection);
+        refuse_queued(&connection, &mut receiver);
+        return;
+    }
+
+    let mut throttle = Throttle::new();
+    let mut sync = server
+        .config
+        .sync_interval
+        .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
 
+    let mut created = server.created.subscribe();
+    let revoke = Arc::clone(&connection.revoke);
+    let queue = Arc::clone(&connection.channel);
+    let mut lagged = false;
     loop {
+        let next_expiry = connection
+            .expiries
+            .front()
+            .map(|(deadline, _)| *deadline)
+            .into_iter()
+            .chain(connection.held_joins.first().map(|held| held.deadline))
+            .min();
         tokio::select! {
-            Some(line) = async { lines.next_line().await.unwrap() } => {
+            line = lines.next_line() => {
+                // The peer hung up
+                let Ok(Some(line)) = line else { break };
+                if !throttle.admit(&server.limits()).await {
+                    let _ = writer.write_all(b"FEDERROR command-rate\n").await;
+                    break;
+                }
                 let req = match parse_server(&line) {
                     Some(r) => r,
-                    None => continue,
+                    None => {
+                        // Once a link is federated it only ever speaks the federation protocol
+                        if parse_client(&line).is_some() {
+                            let _ = writer.write_all(b"FEDERROR wrong-protocol\n").await;
+                        }
+                        continue;
+                    }
                 };
-                process_server_request(server, &mut connection, &mut writer, req).await;
+                let msg = process_server_request(server, &mut connection, req);
+                reply(server, &mut connection, &mut writer, &mut receiver, msg).await;
+                if connection.handshake.closing() {
+                    break;
+                }
             },
-            Some(msg) = receiver.recv() => {
-                match msg {
-                    ServerMessage::Message(msg) => {
-                        writer.write_all(msg.as_bytes()).await.unwrap();
-                    }
-                    ServerMessage::CallbackMessage { channel, user, response, message } => {
-                        writer.write_all(message.as_bytes()).await.unwrap();
-                        connection.callbacks.insert((user, response), channel);
+            Some(msg) = receiver.recv() => forward(server, &mut connection, &mut writer, msg).await,
+            _ = async {
+                match next_expiry {
+                    Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                expire_callbacks(&mut connection);
+                let now = Instant::now();
+                for msg in release_joins(server, &mut connection, |held| held.deadline <= now) {
+                    reply(server, &mut connection, &mut writer, &mut receiver, Some(msg)).await;
+                }
+            },
+            Ok(channel) = created.recv(), if !connection.held_joins.is_empty() => {
+                for msg in release_joins(server, &mut connection, |held| held.channel == channel) {
+                    reply(server, &mut connection, &mut writer, &mut receiver, Some(msg)).await;
+                }
+            },
+            _ = async {
+                match &mut sync {
+                    Some(sync) => sync.tick().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                if let Handshake::Established = connection.handshake {
+                    let mut s = String::from("FEDSYNC");
+                    list_channels(server, &mut s);
+                    reply(server, &mut connection, &mut writer, &mut receiver, Some(s)).await;
+                }
+            },
+            _ = revoke.notified() => {
+                // Forgotten first, so nothing more is routed to the peer after it hears why
+                forget_peer(server, &mut connection);
+                let _ = writer.write_all(ServerRequest::Bye.to_wire().as_bytes()).await;
+                break;
+            },
+            // Not saying why, as the peer is already too far behind to hear it soon
+            _ = queue.lagged.notified() => {
+                eprintln!("{} fell too far behind, dialing it again", connection.server_addr);
+                lagged = true;
+                break;
+            },
+            _ = shutdown.shutdown.recv() => {
+                // Said before forgetting it, so the peer drops us instead of waiting on a dead link
+                if let Handshake::Established = connection.handshake {
+                    let bye = ServerRequest::Bye.to_wire();
+                    if writer.write_all(bye.as_bytes()).await.is_ok() {
+                        shutdown.farewell();
                     }
                 }
+                break;
             },
-            _ = shutdown.shutdown.recv() => break,
             // @Todo this has to be wrong
             else => break,
         }
     }
+
+    forget_peer(server, &mut connection);
+    refuse_queued(&connection, &mut receiver);
+    if lagged {
+        // Hung up first, so the peer has forgotten this link by the time the next one is dialed
+        drop((lines, writer));
+        tokio::time::sleep(REDIAL_DELAY).await;
+        dial(server, connection.server_addr.to_string(), shutdown);
+    }
+}
+
+/// Answers the requests still queued for a closed link, which were never sent so have no
+/// callback for `forget_peer` to answer.
+fn refuse_queued(conn: &ServerConnection, receiver: &mut mpsc::Receiver<ServerMessage>) {
+    receiver.close();
+    while let Ok(msg) = receiver.try_recv() {
+        if let ServerMessage::CallbackMessage {
+            channel, response, ..
+        } = msg
+        {
+            let message = callback_failure(&response, conn.server_addr, "server-gone");
+            let _ = channel.send(ClientMessage::Message(Arc::new(message)));
+        }
+    }
+}
+
+/// Drops everything we know through a federation link once it closes, whether or not its
+/// handshake ever finished, so nothing is sent down a link that no longer exists and nobody
+/// waits on an answer that will never come.
+fn forget_peer(server: &Server, conn: &mut ServerConnection) {
+    for ((_, response), callback) in conn.callbacks.drain() {
+        let message = callback_failure(&response, conn.server_addr, "server-gone");
+        let _ = callback
+            .channel
+            .send(ClientMessage::Message(Arc::new(message)));
+    }
+    conn.expiries.clear();
+    {
+        let mut servers = server.servers.write().unwrap();
+        if servers
+            .get(&conn.server_addr)
+            .is_some_and(|r| Arc::ptr_eq(&r.channel, &conn.channel))
+        {
+            servers.remove(&conn.server_addr);
+        }
+    }
+    for c in server.channels.read().unwrap().values() {
+        c.write().unwrap().users.retain(|_, user| match user {
+            User::Remote(peer) => !Arc::ptr_eq(peer, &conn.channel),
+            User::Local(_) => true,
+        });
+    }
 }
 
 async fn process_client_request(
     server: &Server,
     conn: &mut ClientConnection,
-    writer: &mut OwnedWriteHalf,
+    writer: &mut ClientOutput,
     req: ClientRequest<'_>,
 ) {
+    // Replicas only mirror the primary's state, so changing it is left to the primary
+    if let Some(primary) = server.config.replica_of {
+        if req.is_write() {
+            let msg = format!("RESULT ERROR read-only {}\n", primary);
+            writer.write(&msg).await;
+            return;
+        }
+    }
+
     let msg = match req {
-        ClientRequest::Register { username, password } => register(server, username, password),
-        ClientRequest::Login { username, password } => login(server, conn, username, password),
-        ClientRequest::Join { channel } => join(server, conn, channel),
-        ClientRequest::Create { channel } => create(server, channel),
-        ClientRequest::Say { channel, message } => say(server, conn, channel, message),
+        ClientRequest::Register { username, password } => {
+            register(server, conn, username, password).await
+        }
+        ClientRequest::Login { username, password } => {
+            login(server, conn, username, password).await
+        }
+        ClientRequest::Join { channel } => match join(server, conn, channel) {
+            Some(msg) => msg,
+            None => return,
+        },
+        ClientRequest::Create { channel } => create(server, conn, channel),
+        ClientRequest::JoinAll { channels } => join_all(server, conn, channels),
+        ClientRequest::Say { channel, message } => {
+            match say(server, conn, channel, None, message).await {
+                Some(msg) => msg,
+                None => return,
+            }
+        }
         ClientRequest::Channels => channels(server),
+        ClientRequest::Report {
+            channel,
+            user,
+            reason,
+        } => report(server, conn, channel, user, reason),
+        ClientRequest::Ignore { user } => ignore(server, conn, user),
+        ClientRequest::Unignore { user } => unignore(server, conn, user),
+        ClientRequest::Who { channel, page } => who(server, channel, page),
+        ClientRequest::Limits => limits(server),
+        ClientRequest::Pending => pending(server, conn),
+        ClientRequest::Fetch => fetch(server, conn),
+        ClientRequest::Pause => pause(conn),
+        ClientRequest::Resume => resume(conn),
+        ClientRequest::Alias { old, new } => alias(server, conn, old, new),
+        ClientRequest::Servers => servers(server),
+        ClientRequest::Snapshot { offset } => snapshot(server, conn, offset),
+        ClientRequest::Migrate { user, addr } => migrate(server, conn, user, addr),
+        ClientRequest::Motd => motd(server),
+        ClientRequest::ChanStats { channel } => chan_stats(server, conn, channel),
+        ClientRequest::Profile { user } => profile(server, user),
+        ClientRequest::Remove { channel, user } => remove(server, conn, channel, user),
+        ClientRequest::Defed { addr } => defed(server, conn, addr),
+        ClientRequest::FedPingTest { addr } => match fed_ping_test(server, conn, addr) {
+            Some(msg) => msg,
+            None => return,
+        },
+        ClientRequest::Set { key, value } => set(server, conn, key, value),
+        ClientRequest::Get { key } => get(server, conn, key),
+        ClientRequest::Info { channel } => info(server, channel),
+        ClientRequest::Invite { channel, user } => invite(server, conn, channel, user),
+        ClientRequest::Mode { channel, mode, arg } => set_mode(server, conn, channel, mode, arg),
+        ClientRequest::Modes { channel } => modes(server, channel),
+        ClientRequest::Capacity => capacity(server),
+        ClientRequest::InfoAll { channels } => info_all(server, channels),
+        ClientRequest::Reports => reports(server, conn),
+        // Answered as the `SAY` it is
+        ClientRequest::Reply {
+            channel,
+            parent,
+            message,
+        } => match say(server, conn, channel, Some(parent), message).await {
+            Some(msg) => msg,
+            None => return,
+        },
+        ClientRequest::CancelJoin { channel } => match cancel_join(server, conn, channel) {
+            Some(msg) => msg,
+            None => return,
+        },
+        ClientRequest::SetName { display } => set_name(server, conn, display).await,
     };
-    writer.write_all(msg.as_bytes()).await.unwrap();
+    writer.write(&msg).await;
 }
 
 async fn process_client(
     server: &Server,
-    mut lines: Lines<BufReader<OwnedReadHalf>>,
-    mut writer: OwnedWriteHalf,
+    mut input: ClientInput,
+    mut writer: ClientOutput,
     mut shutdown: Shutdown,
-    initial_request: ClientRequest<'_>,
+    initial_request: Option<ClientRequest<'_>>,
+    peer_addr: SocketAddr,
 ) {
-    let addr = lines.get_ref().get_ref().local_addr().unwrap();
-    let (sender, mut receiver) = mpsc::unbounded_channel::<Arc<String>>();
+    let (queue, mut receiver) = ClientQueue::new(server.config.client_queue);
 
     let mut connection = ClientConnection {
         username: None,
-        channel: Arc::new(sender),
-        server_addr: addr,
+        display: None,
+        channel: Arc::new(queue),
+        peer_addr,
+        recent_says: Default::default(),
+        paused: None,
+        subscriptions: Default::default(),
     };
 
-    process_client_request(server, &mut connection, &mut writer, initial_request).await;
+    if let Some(req) = initial_request {
+        process_client_request(server, &mut connection, &mut writer, req).await;
+    }
 
-    loop {
+    let mut throttle = Throttle::new();
+    // Requests handled since replies were last sent
+    let mut batched = 0;
+    'serve: loop {
         tokio::select! {
-            Some(line) = async { lines.next_line().await.unwrap() } => {
-                let req = match parse_client(&line) {
-                    Some(r) => r,
-                    None => continue,
+            frame = input.next() => {
+                // The client hung up
+                let Ok(Some(frame)) = frame else { break };
+                let limits = server.limits();
+                // Not holding on to replies while waiting
+                if throttle.full(&limits) {
+                    writer.flush().await;
+                }
+                if !throttle.admit(&limits).await {
+                    writer.write("RESULT ERROR command-rate\n").await;
+                    break;
+                }
+                let req = match &frame {
+                    Frame::Text(line) => parse_client(line),
+                    Frame::Binary(body) => decode_client(body),
+                    Frame::Ping(payload) => {
+                        writer.pong(payload).await;
+                        continue;
+                    }
+                    Frame::Close(payload) => {
+                        writer.close(payload).await;
+                        break;
+                    }
+                };
+                let Some(req) = req else {
+                    let verb = match &frame {
+                        // Once a connection is a client's it only ever speaks the client protocol
+                        Frame::Text(line) if parse_server(line).is_some() => {
+                            writer.write("RESULT ERROR wrong-protocol\n").await;
+                            continue;
+                        }
+                        _ if server.config.unknown_commands == UnknownCommands::Ignore => continue,
+                        Frame::Text(line) => line.split(' ').next().unwrap_or_default().to_string(),
+                        // Binary requests are named by their opcode
+                        Frame::Binary(body) => body.first().map_or(String::new(), u8::to_string),
+                        Frame::Ping(_) | Frame::Close(_) => continue,
+                    };
+                    writer.write(&format!("RESULT ERROR unknown-command {}\n", verb)).await;
+                    continue;
                 };
                 process_client_request(server, &mut connection, &mut writer, req).await;
+                if server.config.ordered_results {
+                    while let Ok(msg) = receiver.try_recv() {
+                        if !push(&mut connection, &mut writer, msg).await {
+                            break 'serve;
+                        }
+                    }
+                }
+                // A long burst still goes out in pieces, and lets other connections have a turn
+                batched += 1;
+                if batched == MAX_BATCH {
+                    writer.flush().await;
+                    tokio::task::yield_now().await;
+                    batched = 0;
+                }
             },
+            // Still drained while paused, so that what's held stays bounded
             Some(msg) = receiver.recv() => {
-                writer.write_all(msg.as_bytes()).await.unwrap();
+                if !push(&mut connection, &mut writer, msg).await {
+                    break;
+                }
             },
+            (channel, msg) = connection.subscriptions.next(),
+                if !connection.subscriptions.is_empty() =>
+            {
+                match msg {
+                    Ok(msg) => {
+                        if !is_member(server, &connection, &channel) {
+                            connection.subscriptions.remove(&channel);
+                        } else if !ignores(server, &connection, &msg.from) {
+                            let message = ClientMessage::Message(Arc::clone(&msg.message));
+                            push(&mut connection, &mut writer, message).await;
+                        }
+                    }
+                    // It's too slow to keep up with the channel, and has missed messages
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        writer.write("RESULT ERROR lagged\n").await;
+                        break;
+                    }
+                    // The channel is gone
+                    Err(broadcast::error::RecvError::Closed) => {
+                        connection.subscriptions.remove(&channel);
+                    }
+                }
+            }
             _ = shutdown.shutdown.recv() => break,
+            // Only once nothing else is ready, so whatever else is ready goes out with it
+            _ = async {
+                tokio::task::yield_now().await;
+                writer.flush().await;
+            }, if writer.buffered() => batched = 0,
             else => break,
         }
     }
+    writer.flush().await;
+
+    if let Some(username) = connection.username {
+        log_out(server, &connection.channel, &username);
+    }
+}
+
+/// Ends a user's session on the connection with this channel, unless they've since logged in
+/// on another connection, whose session goes on.
+fn log_out(server: &Server, channel: &ClientChannel, username: &Arc<String>) {
+    let mut user_conns = server.user_conns.write().unwrap();
+    if user_conns
+        .get(username)
+        .is_some_and(|c| Arc::ptr_eq(c, channel))
+    {
+        user_conns.remove(username);
+        server.display_names.write().unwrap().remove(username);
+        // Inactivity counts from when they were last around, not when they logged in
+        server
+            .last_seen
+            .write()
+            .unwrap()
+            .insert(Arc::clone(username), Instant::now());
+    }
+}
+
+/// Finishes opening a WebSocket whose HTTP request line has been read, then serves the client
+/// over it.
+async fn upgrade(
+    server: &Server,
+    mut lines: LineReader,
+    mut writer: OwnedWriteHalf,
+    shutdown: Shutdown,
+    peer_addr: SocketAddr,
+) {
+    let key = async {
+        let mut handshake = websocket::Handshake::default();
+        loop {
+            match lines.next_line().await {
+                // The end of the headers
+                Ok(Some(header)) if header.is_empty() => return handshake.key(),
+                Ok(Some(header)) => handshake.header(&header),
+                Ok(None) | Err(_) => return None,
+            }
+        }
+    };
+    let Ok(key) = tokio::time::timeout(server.config.first_line_timeout, key).await else {
+        return;
+    };
+    let Some(key) = key else {
+        let _ = writer
+            .write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")
+            .await;
+        return;
+    };
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        websocket::accept(&key)
+    );
+    if writer.write_all(response.as_bytes()).await.is_err() {
+        return;
+    }
+    let input = ClientInput::WebSocket(WebSocketFrames {
+        reader: lines.into_inner(),
+        buf: Vec::new(),
+        message: Vec::new(),
+    });
+    let writer = ClientOutput::new(writer, Framing::WebSocket);
+    let Some(_counted) = Counted::admit(server, ConnectionKind::Client) else {
+        return refuse_full(writer).await;
+    };
+    process_client(server, input, writer, shutdown, None, peer_addr).await;
+}
+
+/// Turns a client away for there being `--max-connections` already.
+async fn refuse_full(mut writer: ClientOutput) {
+    writer.write("RESULT ERROR server-full\n").await;
+    writer.flush().await;
 }
 
-async fn process(server: &Server, socket: TcpStream, mut shutdown: Shutdown) {
+/// Serves a connection to or from `peer_addr`, whose address was known when it was made.
+async fn process(
+    server: &Arc<Server>,
+    socket: TcpStream,
+    peer_addr: SocketAddr,
+    mut shutdown: Shutdown,
+    outbound: bool,
+) {
     let (reader, writer) = socket.into_split();
-    let mut lines = BufReader::new(reader).lines();
+    let mut lines = LineReader::new(reader);
 
-    tokio::select! {
-        line = async { lines.next_line().await.unwrap().unwrap() } => {
-            let req = match parse(&line) {
-                Some(r) => r,
-                None => panic!(),
-            };
-            match req {
-                Request::Client(r) => process_client(server, lines, writer, shutdown, r).await,
-                Request::Server(r) => process_server(server, lines, writer, shutdown, r).await,
+    // Lines that aren't a request are skipped, but only for so long
+    let first_line = async {
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line))
+                    if line == BINARY_MODE
+                        || parse(&line).is_some()
+                        || (server.config.websocket && websocket::is_upgrade(&line)) =>
+                {
+                    return Some(line)
+                }
+                Ok(Some(_)) => continue,
+                // Gone before saying anything we understand
+                Ok(None) | Err(_) => return None,
             }
         }
+    };
+    // Returning drops the socket, which closes the connection
+    let line = tokio::select! {
+        line = tokio::time::timeout(server.config.first_line_timeout, first_line) => line,
         _ = shutdown.shutdown.recv() => return,
+    };
+    let Ok(Some(line)) = line else { return };
+
+    if websocket::is_upgrade(&line) {
+        upgrade(server, lines, writer, shutdown, peer_addr).await;
+        return;
+    }
+    if line == BINARY_MODE {
+        let mut writer = ClientOutput::new(writer, Framing::Text);
+        writer.write(&format!("RESULT {} 1\n", BINARY_MODE)).await;
+        writer.framing = Framing::Binary;
+        // Keeps the reader's buffer, which may already hold the first frame
+        let reader = lines.into_inner();
+        let input = ClientInput::Binary(Frames {
+            reader,
+            buf: Vec::new(),
+        });
+        let Some(_counted) = Counted::admit(server, ConnectionKind::Client) else {
+            return refuse_full(writer).await;
+        };
+        process_client(server, input, writer, shutdown, None, peer_addr).await;
+        return;
+    }
+    let Some(req) = parse(&line) else { return };
+    let Some(_counted) = Counted::admit(server, req.kind()) else {
+        return refuse_full(ClientOutput::new(writer, Framing::Text)).await;
+    };
+    match req {
+        Request::Client(r) => {
+            let writer = ClientOutput::new(writer, Framing::Text);
+            let input = ClientInput::Text(lines);
+            process_client(server, input, writer, shutdown, Some(r), peer_addr).await
+        }
+        Request::Server(r) => {
+            process_server(server, lines, writer, shutdown, r, peer_addr, outbound).await
+        }
     }
 }
 
+/// What the tasks did about a shutdown, for `main` to report once they are done.
+#[derive(Default)]
+struct Tally {
+    /// Tasks still running
+    running: AtomicUsize,
+    /// Peers told `FEDBYE` as their link closed for the shutdown
+    farewells: AtomicUsize,
+}
+
 struct Shutdown {
     _sender: mpsc::Sender<()>,
     shutdown: broadcast::Receiver<()>,
+    tally: Arc<Tally>,
+}
+
+impl Shutdown {
+    fn new(
+        sender: &mpsc::Sender<()>,
+        shutdown: &broadcast::Sender<()>,
+        tally: &Arc<Tally>,
+    ) -> Self {
+        tally.running.fetch_add(1, Ordering::SeqCst);
+        Shutdown {
+            _sender: sender.clone(),
+            shutdown: shutdown.subscribe(),
+            tally: Arc::clone(tally),
+        }
+    }
+
+    fn farewell(&self) {
+        self.tally.farewells.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Shutdown {
+    fn drop(&mut self) {
+        self.tally.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Applies whatever can change while running from the configuration file, and says what
+/// can't. The whole configuration is resolved again from `args` and `env`, the command line and
+/// environment the server started with, so what they set still overrides the file. `previous`
+/// is what the file said the last time it was read, and is updated.
+fn reload(
+    server: &Server,
+    args: &[String],
+    env: &[(String, String)],
+    previous: &mut HashMap<String, String>,
+) {
+    let Some(path) = &server.config.config_file else {
+        eprintln!("No configuration file to reload");
+        return;
+    };
+    let settings = match read_config_file(path) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Failed to reload {}: {}", path, e);
+            return;
+        }
+    };
+    // Resolved in full first, so a bad value leaves the running settings alone
+    let next = match Config::resolve(args.iter().cloned(), env.iter().cloned()) {
+        Ok(next) => next,
+        Err(e) => {
+            eprintln!("Failed to reload {}: {}", path, e);
+            return;
+        }
+    };
+
+    let overridden: HashSet<String> = args
+        .iter()
+        .filter_map(|arg| flag_option(arg).map(str::to_string))
+        .chain(env.iter().filter_map(|(name, _)| env_option(name)))
+        .collect();
+    for (key, value) in &settings {
+        if previous.get(key) == Some(value) {
+            continue;
+        }
+        if !RELOADABLE.contains(&key.as_str()) {
+            eprintln!("Changing --{} needs a restart", key);
+        } else if overridden.contains(key) {
+            eprintln!(
+                "Not reloading --{}, as it's set by a flag or the environment",
+                key
+            );
+        } else {
+            println!("Reloaded --{}={}", key, value);
+        }
+    }
+
+    *server.config.limits.write().unwrap() = next.limits.into_inner().unwrap();
+    *server.config.admins.write().unwrap() = next.admins.into_inner().unwrap();
+    *previous = settings.into_iter().collect();
+}
+
+/// Everyone this server federates with: each line of the peer file, and the primary if this
+/// is a replica, which mirrors it over an ordinary federation link.
+fn peer_list(server: &Server) -> std::io::Result<Vec<String>> {
+    let mut peers = Vec::new();
+    if let Some(file) = &server.config.peers {
+        let string = std::fs::read_to_string(file)?;
+        peers.extend(string.lines().map(str::to_string));
+    }
+    if let Some(primary) = server.config.replica_of {
+        peers.push(primary.to_string());
+    }
+    Ok(peers)
+}
+
+/// How long a link dropped for lagging waits to be dialed again.
+const REDIAL_DELAY: Duration = Duration::from_millis(100);
+
+fn dial(server: &Arc<Server>, line: String, shutdown: Shutdown) {
+    let server = Arc::clone(server);
+    tokio::spawn(async move {
+        let connected = TcpStream::connect(&line).await;
+        match connected.and_then(|socket| Ok((socket.peer_addr()?, socket))) {
+            Ok((peer_addr, _)) if peer_addr == server.addr => {
+                eprintln!("Refusing to federate with ourselves at {}", line)
+            }
+            Ok((peer_addr, mut socket)) => {
+                let addr = server.addr.to_string();
+                let out = ServerRequest::Out { addr: Some(&addr) }.to_wire();
+                if let Err(e) = socket.write_all(out.as_bytes()).await {
+                    return eprintln!("Lost the connection to {} as it opened: {}", line, e);
+                }
+                process(&server, socket, peer_addr, shutdown, true).await
+            }
+            Err(e) => eprintln!("Failed to connect to server at {}: {}", line, e),
+        }
+    });
+}
+
+/// Resolves each time the operator asks for the configuration to be reloaded with `SIGHUP`.
+/// Elsewhere there's no such signal, and it never resolves.
+struct Hangup {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Hangup {
+    fn new() -> Self {
+        Hangup {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).unwrap(),
+        }
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.signal.recv().await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    let port: u16 = std::env::args()
-        .nth(1)
-        .expect("Port number")
-        .parse()
-        .expect("Provided port is a valid number");
-    let server = Arc::new(Server::new(port));
-    let listener = TcpListener::bind(("127.0.0.1", server.port)).await.unwrap();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // Variables that aren't unicode can't be ours
+    let mut env: Vec<(String, String)> = std::env::vars_os()
+        .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+        .collect();
+    let config = Config::resolve(args.iter().cloned(), env.iter().cloned()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    // Those that set no option were warned about once, and needn't be again on every reload
+    env.retain(|(name, _)| env_option(name).is_some_and(|key| OPTIONS.contains(&key.as_str())));
+    let listener = TcpListener::bind(("127.0.0.1", config.port)).await.unwrap();
+    let server = Arc::new(Server::new(config, listener.local_addr().unwrap()));
+    server.load_motd();
+    // Set up before anyone learns our address, since a `SIGHUP` without it kills the process
+    let mut hangup = Hangup::new();
 
     // For testing
-    println!("{}", listener.local_addr().unwrap());
+    println!("{}", server.addr);
 
     let (task_send, mut task_recv) = mpsc::channel(1);
     let (shutdown_send, _) = broadcast::channel(1);
+    let tally = Arc::new(Tally::default());
 
-    if let Some(file) = std::env::args().nth(2) {
-        let string = std::fs::read_to_string(file).expect("Invalid configuration file path");
-        for line in string.lines() {
-            let server = Arc::clone(&server);
-            let line = line.to_string();
-            let shutdown = Shutdown {
-                _sender: task_send.clone(),
-                shutdown: shutdown_send.subscribe(),
-            };
-            tokio::spawn(async move {
-                match TcpStream::connect(&line).await {
-                    Ok(mut socket) => {
-                        socket.write_all(b"FEDERATEOUT\n").await.unwrap();
-                        process(&*server, socket, shutdown).await
-                    }
-                    Err(e) => eprintln!("Failed to connect to server at {}: {}", line, e),
-                }
-            });
-        }
+    let mut dialed = HashSet::new();
+    for line in peer_list(&server).expect("Invalid configuration file path") {
+        dialed.insert(line.clone());
+        dial(
+            &server,
+            line,
+            Shutdown::new(&task_send, &shutdown_send, &tally),
+        );
     }
 
+    let mut settings: HashMap<String, String> = match &server.config.config_file {
+        Some(path) => read_config_file(path)
+            .unwrap_or_default()
+            .into_iter()
+            .collect(),
+        None => Default::default(),
+    };
+
+    let mut sweep = server.config.account_ttl.map(|ttl| {
+        let period = ttl.min(Duration::from_secs(60));
+        tokio::time::interval_at(tokio::time::Instant::now() + period, period)
+    });
+
+    // While set, accepting waits until then. Everything else carries on meanwhile.
+    let mut backoff: Option<tokio::time::Instant> = None;
     loop {
         tokio::select! {
-            (socket, _) = async { listener.accept().await.unwrap() } => {
-                let server = Arc::clone(&server);
-                let shutdown = Shutdown {
-                    _sender: task_send.clone(),
-                    shutdown: shutdown_send.subscribe(),
-                };
-                tokio::spawn(async move {
-                    process(&*server, socket, shutdown).await;
-                });
+            accepted = listener.accept(), if backoff.is_none() => match accepted {
+                Ok((socket, peer_addr)) => {
+                    let server = Arc::clone(&server);
+                    let shutdown = Shutdown::new(&task_send, &shutdown_send, &tally);
+                    tokio::spawn(async move {
+                        process(&server, socket, peer_addr, shutdown, false).await;
+                    });
+                }
+                // A connection that can't be taken yet stays queued for when it can, and those
+                // already open get to finish and make room. Clients over `--max-connections` are
+                // turned away once they say what they are, not by holding off accepting: that
+                // would keep out peers, which aren't held to it, and a fixed set of permits
+                // wouldn't follow the limit when a reload changes it.
+                Err(e) => {
+                    eprintln!("Failed to accept a connection, waiting {:?}: {}", ACCEPT_BACKOFF, e);
+                    backoff = Some(tokio::time::Instant::now() + ACCEPT_BACKOFF);
+                }
+            },
+            _ = async {
+                match backoff {
+                    Some(until) => tokio::time::sleep_until(until).await,
+                    None => std::future::pending().await,
+                }
+            } => backoff = None,
+            _ = hangup.recv() => {
+                reload(&server, &args, &env, &mut settings);
+                server.load_motd();
+                // Peers can be added while running, but dropping them needs a restart
+                match peer_list(&server) {
+                    Ok(peers) => {
+                        for line in peers {
+                            if dialed.insert(line.clone()) {
+                                let shutdown = Shutdown::new(&task_send, &shutdown_send, &tally);
+                                dial(&server, line, shutdown);
+                            }
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to reread the peer file: {}", e),
+                }
             }
+            _ = async {
+                match &mut sweep {
+                    Some(sweep) => sweep.tick().await,
+                    None => std::future::pending().await,
+                }
+            } => expire_accounts(&server, Instant::now()),
             _ = tokio::signal::ctrl_c() => break,
         }
     }
 
+    let active = tally.running.load(Ordering::SeqCst);
+    let started = Instant::now();
     drop(task_send);
     drop(shutdown_send);
-    let _ = task_recv.recv().await;
-    println!("Shut Down cleanly!")
+    // A task stuck somewhere that doesn't watch for shutdown, a write to a client that stopped
+    // reading for example, would otherwise keep us waiting forever
+    let grace = server.config.shutdown_grace;
+    let clean = tokio::time::timeout(grace, task_recv.recv()).await.is_ok();
+    println!(
+        "Shutdown: {} connection(s) active, {} peer(s) sent FEDBYE, drained in {:?}",
+        active,
+        tally.farewells.load(Ordering::SeqCst),
+        started.elapsed()
+    );
+    if clean {
+        println!("Shut Down cleanly!");
+    } else {
+        println!(
+            "Gave up waiting on {} task(s) after {:?}",
+            tally.running.load(Ordering::SeqCst),
+            grace
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Knows one account, and remembers every password it was asked to check.
+    #[derive(Default)]
+    struct MockAuth {
+        verified: Arc<Mutex<Vec<(String, String)>>>,
+    }
+
+    impl AuthBackend for MockAuth {
+        fn register<'a>(&'a self, _: &'a str, _: &'a str) -> AuthFuture<'a, bool> {
+            Box::pin(std::future::ready(false))
+        }
+
+        fn verify<'a>(&'a self, username: &'a str, password: &'a str) -> AuthFuture<'a, bool> {
+            let pair = (username.to_string(), password.to_string());
+            self.verified.lock().unwrap().push(pair);
+            Box::pin(std::future::ready(username == "u" && password == "p"))
+        }
+
+        fn exists<'a>(&'a self, username: &'a str) -> AuthFuture<'a, bool> {
+            Box::pin(std::future::ready(username == "u"))
+        }
+    }
+
+    fn connection() -> ClientConnection {
+        let (queue, _) = ClientQueue::new(1);
+        ClientConnection {
+            username: None,
+            display: None,
+            channel: Arc::new(queue),
+            peer_addr: "127.0.0.1:1".parse().unwrap(),
+            recent_says: Default::default(),
+            paused: None,
+            subscriptions: Default::default(),
+        }
+    }
+
+    #[test]
+    fn variables_that_set_no_option_are_skipped() {
+        let env = [
+            ("CHAT_TOKEN", "abc"),
+            ("CHAT_MAX_MESSAGE", "7"),
+            ("PATH", "/bin"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        let config = Config::resolve(std::iter::once(String::from("0")), env).unwrap();
+        assert_eq!(config.limits.read().unwrap().max_message, 7);
+    }
+
+    #[test]
+    fn every_option_is_one_set_takes() {
+        for key in OPTIONS {
+            if let Err(e) = Config::default().set(key, "?") {
+                assert!(!e.starts_with("Unknown option"), "{}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn reload_leaves_what_flags_and_the_environment_set() {
+        let path = std::env::temp_dir().join(format!("chat_server_conf_{}", std::process::id()));
+        std::fs::write(&path, "max-message=50\nmax-name=5\nadmins=b\n").unwrap();
+        let args: Vec<String> = [
+            "0".to_string(),
+            format!("--config={}", path.display()),
+            "--max-message=100".to_string(),
+            "--admins=a".to_string(),
+        ]
+        .into();
+        let env = [("CHAT_RATE".to_string(), "3".to_string())];
+        let config = Config::resolve(args.iter().cloned(), env.iter().cloned()).unwrap();
+        let server = Server::new(config, "127.0.0.1:2".parse().unwrap());
+        let mut previous = read_config_file(path.to_str().unwrap())
+            .unwrap()
+            .into_iter()
+            .collect();
+
+        std::fs::write(&path, "max-message=60\nmax-name=6\nrate=9\nadmins=c\n").unwrap();
+        reload(&server, &args, &env, &mut previous);
+        std::fs::remove_file(&path).unwrap();
+
+        let limits = server.limits();
+        assert_eq!(
+            (limits.max_message, limits.max_name, limits.rate),
+            (100, 6, 3)
+        );
+        let admins = server.config.admins.read().unwrap();
+        let mut admins: Vec<_> = admins.iter().map(String::as_str).collect();
+        admins.sort_unstable();
+        assert_eq!(admins, ["a", "c"]);
+    }
+
+    #[test]
+    fn seconds_that_are_no_duration_are_refused() {
+        let resolve = |flag: &str| {
+            let args = ["0", flag].into_iter().map(String::from);
+            Config::resolve(args, std::iter::empty())
+        };
+        for flag in [
+            "--shutdown-grace=-1",
+            "--callback-timeout=inf",
+            "--first-line-timeout=NaN",
+            "--sync-interval=inf",
+            "--history-max-age=-1",
+            "--account-ttl=1e300",
+        ] {
+            assert!(resolve(flag).is_err(), "{} was taken", flag);
+        }
+        let config = resolve("--sync-interval=0").unwrap();
+        assert_eq!(config.sync_interval, None);
+        let config = resolve("--shutdown-grace=0.5").unwrap();
+        assert_eq!(config.shutdown_grace, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn history_older_than_max_age_isnt_replayed() {
+        let config = Config {
+            history: 3,
+            history_max_age: Some(Duration::from_secs(60)),
+            ..Config::default()
+        };
+        let channel = Channel::new(None);
+        let start = Instant::now();
+        for (i, said) in [0, 30, 61].into_iter().enumerate() {
+            let msg = Arc::new(format!("RECV u c {}\n", i));
+            channel.record(&config, &msg, start + Duration::from_secs(said));
+        }
+
+        let replay = |secs| channel.replay(&config, start + Duration::from_secs(secs));
+        assert_eq!(replay(61), "RECV u c 1\nRECV u c 2\n");
+        assert_eq!(replay(100), "RECV u c 2\n");
+        assert_eq!(replay(200), "");
+    }
+
+    #[test]
+    fn full_client_queue_lags() {
+        let (queue, mut receiver) = ClientQueue::new(3);
+        let message = |text: &str| ClientMessage::Message(Arc::new(text.to_string()));
+
+        assert!(queue.send(message("a")).is_ok());
+        assert!(queue.send(message("b")).is_ok());
+        // The last place is the client's notice that it lagged
+        assert!(queue.send(message("c")).is_err());
+        assert!(receiver.try_recv().is_ok());
+        // Even once there's room again, as it's on its way out
+        assert!(queue.send(message("d")).is_err());
+        assert!(receiver.try_recv().is_ok());
+        assert!(matches!(receiver.try_recv(), Ok(ClientMessage::Lagged)));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn login_asks_the_backend() {
+        let auth = MockAuth::default();
+        let verified = Arc::clone(&auth.verified);
+        let mut server = Server::new(Config::default(), "127.0.0.1:2".parse().unwrap());
+        server.auth = Box::new(auth);
+        let mut conn = connection();
+
+        assert_eq!(
+            login(&server, &mut conn, "u", "wrong").await,
+            "RESULT LOGIN 0\n"
+        );
+        assert!(conn.username.is_none());
+        assert_eq!(
+            login(&server, &mut conn, "u", "p").await,
+            "RESULT LOGIN 1\n"
+        );
+        assert_eq!(conn.username.as_deref().map(String::as_str), Some("u"));
+        assert!(server
+            .user_conns
+            .read()
+            .unwrap()
+            .contains_key(&"u".to_string()));
+
+        let verified = verified.lock().unwrap();
+        let pairs: Vec<_> = verified
+            .iter()
+            .map(|(u, p)| (u.as_str(), p.as_str()))
+            .collect();
+        assert_eq!(pairs, [("u", "wrong"), ("u", "p")]);
+    }
+
+    #[tokio::test]
+    async fn login_as_someone_else_logs_out_the_last_user() {
+        let server = Server::new(Config::default(), "127.0.0.1:2".parse().unwrap());
+        let mut conn = connection();
+        for user in ["u", "v"] {
+            assert_eq!(
+                register(&server, &conn, user, "p").await,
+                "RESULT REGISTER 1\n"
+            );
+        }
+
+        assert_eq!(
+            login(&server, &mut conn, "u", "p").await,
+            "RESULT LOGIN 1\n"
+        );
+        assert_eq!(
+            login(&server, &mut conn, "v", "p").await,
+            "RESULT LOGIN 1\n"
+        );
+        let user_conns = server.user_conns.read().unwrap();
+        let online: Vec<_> = user_conns.keys().map(|u| u.as_str()).collect();
+        assert_eq!(online, ["v"]);
+    }
+
+    #[tokio::test]
+    async fn accounts_expire_unless_online() {
+        let config = Config {
+            account_ttl: Some(Duration::from_secs(60)),
+            ..Config::default()
+        };
+        let server = Server::new(config, "127.0.0.1:2".parse().unwrap());
+        let mut conn = connection();
+        for user in ["u", "v"] {
+            register(&server, &conn, user, "p").await;
+        }
+        login(&server, &mut conn, "v", "p").await;
+
+        let now = Instant::now();
+        expire_accounts(&server, now);
+        assert!(server.auth.exists("u").await);
+        expire_accounts(&server, now + Duration::from_secs(61));
+        assert!(!server.auth.exists("u").await);
+        // Online however long ago they logged in
+        assert!(server.auth.exists("v").await);
+        let last_seen = server.last_seen.read().unwrap();
+        let kept: Vec<_> = last_seen.keys().map(|u| u.as_str()).collect();
+        assert_eq!(kept, ["v"]);
+    }
+
+    #[tokio::test]
+    async fn register_refuses_names_the_backend_has() {
+        let mut server = Server::new(Config::default(), "127.0.0.1:2".parse().unwrap());
+        server.auth = Box::new(MockAuth::default());
+        let conn = connection();
+
+        assert_eq!(
+            register(&server, &conn, "u", "x").await,
+            "RESULT REGISTER 0\n"
+        );
+        assert!(server.last_seen.read().unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn registering_one_name_at_once_lets_one_through() {
+        let server = Arc::new(Server::new(
+            Config::default(),
+            "127.0.0.1:2".parse().unwrap(),
+        ));
+        let barrier = Arc::new(tokio::sync::Barrier::new(64));
+
+        let tasks: Vec<_> = (0..64)
+            .map(|i| {
+                let (server, barrier) = (Arc::clone(&server), Arc::clone(&barrier));
+                tokio::spawn(async move {
+                    barrier.wait().await;
+                    register(&server, &connection(), "u", &i.to_string()).await
+                })
+            })
+            .collect();
+        let mut registered = 0;
+        for task in tasks {
+            if task.await.unwrap() == "RESULT REGISTER 1\n" {
+                registered += 1;
+            }
+        }
+        assert_eq!(registered, 1);
+    }
+
+    #[tokio::test]
+    async fn message_log_acks_once_written() {
+        let path = std::env::temp_dir().join(format!("chat_server_log_{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let log = MessageLog::open(path).unwrap();
+
+        let ids: Vec<_> = (0..100)
+            .map(|i| log.append("c", "u", None, &i.to_string()).unwrap())
+            .collect();
+        assert_eq!(ids, (1..=100).collect::<Vec<_>>());
+        assert!(log.persisted(100).await);
+        let contents = std::fs::read_to_string(path).unwrap();
+        let logged: Vec<_> = contents
+            .lines()
+            .map(|l| l.split(' ').next().unwrap())
+            .collect();
+        assert_eq!(logged.len(), 100);
+        assert!(logged.iter().zip(&ids).all(|(l, id)| *l == id.to_string()));
+
+        drop(log);
+        let log = MessageLog::open(path).unwrap();
+        assert_eq!(log.append("c", "u", Some(100), "again"), Some(101));
+        assert!(log.persisted(101).await);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn forgotten_peer_is_ignored() {
+        let server = Server::new(Config::default(), "127.0.0.1:2".parse().unwrap());
+        let (queue, _queue) = PeerQueue::new(1);
+        let mut conn = ServerConnection {
+            channel: Arc::new(queue),
+            server_addr: "127.0.0.1:3".parse().unwrap(),
+            peer_addr: "127.0.0.1:3".parse().unwrap(),
+            callbacks: Default::default(),
+            expiries: Default::default(),
+            held_joins: Default::default(),
+            handshake: Handshake::Established,
+            revoke: Default::default(),
+        };
+
+        assert_eq!(fed_sync(&server, &mut conn, "c, d"), None);
+        assert_eq!(fed_presence(&server, &mut conn, "r@127.0.0.1:3", "c"), None);
+        assert_eq!(fed_channels(&server, &mut conn, "c"), None);
+        assert_eq!(fed_new(&server, &mut conn, "c"), None);
+    }
+
+    /// Counts the writes that make it to the socket beneath.
+    struct CountingWriter {
+        socket: OwnedWriteHalf,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let written = Pin::new(&mut self.socket).poll_write(cx, buf);
+            if written.is_ready() {
+                self.writes.fetch_add(1, Ordering::Relaxed);
+            }
+            written
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            Pin::new(&mut self.socket).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            Pin::new(&mut self.socket).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn burst_goes_out_in_few_writes() {
+        const BURST: usize = 500;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (client, mut sender) = client.await.unwrap().into_split();
+        let (socket, peer_addr) = listener.accept().await.unwrap();
+        let (reader, socket) = socket.into_split();
+        let writes = Arc::new(AtomicUsize::new(0));
+        let writer = CountingWriter {
+            socket,
+            writes: Arc::clone(&writes),
+        };
+
+        let server = Arc::new(Server::new(
+            Config::default(),
+            "127.0.0.1:2".parse().unwrap(),
+        ));
+        let (task_send, _tasks) = mpsc::channel(1);
+        let (shutdown_send, _) = broadcast::channel(1);
+        let tally = Arc::new(Tally::default());
+        let shutdown = Shutdown::new(&task_send, &shutdown_send, &tally);
+        tokio::spawn(async move {
+            let input = ClientInput::Text(LineReader::new(reader));
+            let output = ClientOutput::new(writer, Framing::Text);
+            process_client(&server, input, output, shutdown, None, peer_addr).await;
+        });
+
+        let mut burst = String::from("REGISTER u p\nLOGIN u p\nCREATE c\nJOIN c\n");
+        burst.push_str(&"SAY c m\n".repeat(BURST));
+        sender.write_all(burst.as_bytes()).await.unwrap();
+        // Each message is answered and heard
+        let mut lines = LineReader::new(client);
+        for _ in 0..4 + 2 * BURST {
+            lines.next_line().await.unwrap().unwrap();
+        }
+        let writes = writes.load(Ordering::Relaxed);
+        // Going out a batch at a time or so, where unbuffered it would be a write a line
+        assert!(writes <= BURST / 10, "{} writes", writes);
+    }
+
+    #[tokio::test]
+    async fn peer_that_falls_behind_is_dialed_again() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        let config = Config {
+            peer_queue: 1,
+            ..Config::default()
+        };
+        let server = Arc::new(Server::new(config, "127.0.0.1:2".parse().unwrap()));
+        let (task_send, _tasks) = mpsc::channel(1);
+        let (shutdown_send, _) = broadcast::channel(1);
+        let tally = Arc::new(Tally::default());
+        let shutdown = Shutdown::new(&task_send, &shutdown_send, &tally);
+        dial(&server, peer.to_string(), shutdown);
+
+        let (socket, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = socket.into_split();
+        let mut lines = LineReader::new(reader);
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            "FEDOUT 127.0.0.1:2"
+        );
+        writer.write_all(b"FEDCONFIRM\n").await.unwrap();
+        let channels = lines.next_line().await.unwrap().unwrap();
+        assert!(channels.starts_with("FEDCHANNELS"));
+
+        // All at once, before the link's task gets a turn to write any of it
+        let channel = Arc::clone(&server.servers.read().unwrap()[&peer].channel);
+        for _ in 0..3 {
+            assert!(send_to_peer(&channel, Arc::new("FEDPING t\n".to_string())));
+        }
+
+        // Hung up on, and dialed again for a fresh start
+        while lines.next_line().await.unwrap().is_some() {}
+        let accepted = tokio::time::timeout(Duration::from_secs(5), listener.accept());
+        let (socket, _) = accepted.await.unwrap().unwrap();
+        let mut lines = LineReader::new(socket.into_split().0);
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            "FEDOUT 127.0.0.1:2"
+        );
+    }
+
+    #[tokio::test]
+    async fn say_to_a_busy_peer_is_answered() {
+        let server = Server::new(Config::default(), "127.0.0.1:2".parse().unwrap());
+        let peer: SocketAddr = "127.0.0.1:3".parse().unwrap();
+        let (queue, _queue) = PeerQueue::new(1);
+        let channel: ServerChannel = Arc::new(queue);
+        let members = HashMap::from([(
+            "c".to_string(),
+            HashSet::from(["u@127.0.0.1:2".to_string()]),
+        )]);
+        server.servers.write().unwrap().insert(
+            peer,
+            RemoteServer {
+                channel: Arc::clone(&channel),
+                revoke: Default::default(),
+                outbound: true,
+                channels: Default::default(),
+                members: RwLock::new(members),
+            },
+        );
+        let mut conn = connection();
+        conn.username = Some(Arc::new("u".to_string()));
+
+        assert!(send_to_peer(&channel, Arc::new("FEDPING\n".to_string())));
+        assert_eq!(
+            say(&server, &mut conn, "c:127.0.0.1:3", None, "hi")
+                .await
+                .as_deref(),
+            Some("RESULT SAY c:127.0.0.1:3 0 server-busy\n")
+        );
+        assert!(server.servers.read().unwrap().contains_key(&peer));
+
+        drop(_queue);
+        assert_eq!(
+            say(&server, &mut conn, "c:127.0.0.1:3", None, "hi")
+                .await
+                .as_deref(),
+            Some("RESULT SAY c:127.0.0.1:3 0 server-gone\n")
+        );
+        assert!(server.servers.read().unwrap().is_empty());
+    }
 }
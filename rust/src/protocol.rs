@@ -0,0 +1,1066 @@
+//! The line protocol spoken by clients and federated servers, kept apart from the server so
+//! the fuzz harness in `fuzz/` can build it on its own.
+//!
+//! Clients may instead speak a binary framing of the same requests. Each frame is a big-endian
+//! `u32` length followed by that many bytes. A request frame holds an opcode byte and then each
+//! argument as a big-endian `u16` length and its bytes. The server's frames hold one of the
+//! lines it would otherwise have sent, without the newline.
+
+use std::borrow::Cow;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientRequest<'a> {
+    Register {
+        username: &'a str,
+        password: &'a str,
+    },
+    Login {
+        username: &'a str,
+        password: &'a str,
+    },
+    Join {
+        channel: &'a str,
+    },
+    Create {
+        channel: &'a str,
+    },
+    Say {
+        channel: &'a str,
+        message: &'a str,
+    },
+    Channels,
+    Report {
+        channel: &'a str,
+        user: &'a str,
+        reason: &'a str,
+    },
+    Ignore {
+        user: &'a str,
+    },
+    Unignore {
+        user: &'a str,
+    },
+    Who {
+        channel: &'a str,
+        /// The offset and count of a page of the member list, rather than all of it
+        page: Option<(usize, usize)>,
+    },
+    Limits,
+    SetName {
+        display: &'a str,
+    },
+    Pending,
+    Fetch,
+    Alias {
+        old: &'a str,
+        new: &'a str,
+    },
+    Pause,
+    Resume,
+    Servers,
+    Defed {
+        addr: &'a str,
+    },
+    /// An empty value clears the setting
+    Set {
+        key: &'a str,
+        value: &'a str,
+    },
+    Get {
+        key: &'a str,
+    },
+    Info {
+        channel: &'a str,
+    },
+    Mode {
+        channel: &'a str,
+        mode: &'a str,
+        arg: Option<&'a str>,
+    },
+    FedPingTest {
+        addr: &'a str,
+    },
+    Invite {
+        channel: &'a str,
+        user: &'a str,
+    },
+    /// Separated by commas
+    JoinAll {
+        channels: &'a str,
+    },
+    Motd,
+    ChanStats {
+        channel: &'a str,
+    },
+    Profile {
+        user: &'a str,
+    },
+    Remove {
+        channel: &'a str,
+        user: &'a str,
+    },
+    /// The page of channels starting at `offset`
+    Snapshot {
+        offset: usize,
+    },
+    Migrate {
+        user: &'a str,
+        addr: &'a str,
+    },
+    Modes {
+        channel: &'a str,
+    },
+    /// Stops waiting on a federated join's answer
+    CancelJoin {
+        channel: &'a str,
+    },
+    Capacity,
+    /// Says a message in reply to the one with the id `parent`
+    Reply {
+        channel: &'a str,
+        parent: u64,
+        message: &'a str,
+    },
+    /// Separated by commas
+    InfoAll {
+        channels: &'a str,
+    },
+    Reports,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ServerResult<'a> {
+    Join {
+        user: &'a str,
+        channel: &'a str,
+        status: &'a str,
+    },
+    Say {
+        user: &'a str,
+        channel: &'a str,
+        status: &'a str,
+        msg: &'a str,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ServerRequest<'a> {
+    Out {
+        addr: Option<&'a str>,
+    },
+    Reject {
+        reason: &'a str,
+    },
+    Confirm,
+    Channels {
+        channels: &'a str,
+    },
+    Sync {
+        channels: &'a str,
+    },
+    New {
+        channel: &'a str,
+    },
+    Join {
+        user: &'a str,
+        channel: &'a str,
+    },
+    /// Sent as `FEDREPLY` when it replies to the message with the id `parent`
+    Say {
+        user: &'a str,
+        channel: &'a str,
+        parent: Option<u64>,
+        msg: &'a str,
+    },
+    /// Sent as `FEDRECVREPLY` when it replies to the message with the id `parent`
+    Recv {
+        to_user: &'a str,
+        from_user: &'a str,
+        channel: &'a str,
+        parent: Option<u64>,
+        msg: &'a str,
+    },
+    Result(ServerResult<'a>),
+    Presence {
+        user: &'a str,
+        channel: &'a str,
+    },
+    /// A member is no longer in one of the sender's channels
+    Leave {
+        user: &'a str,
+        channel: &'a str,
+    },
+    /// The peer is ending the federation and closing the link
+    Bye,
+    /// Answered with a `Pong` carrying the same token
+    Ping {
+        token: &'a str,
+    },
+    Pong {
+        token: &'a str,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Request<'a> {
+    Client(ClientRequest<'a>),
+    Server(ServerRequest<'a>),
+}
+
+/// Whether a connection is from a client or a federated server, decided by its first line.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConnectionKind {
+    Client,
+    Server,
+}
+
+impl Request<'_> {
+    pub fn kind(&self) -> ConnectionKind {
+        match self {
+            Request::Client(_) => ConnectionKind::Client,
+            Request::Server(_) => ConnectionKind::Server,
+        }
+    }
+}
+
+impl ClientRequest<'_> {
+    /// The line, newline included, that parses back into this request.
+    pub fn to_wire(&self) -> String {
+        use ClientRequest::*;
+
+        match self {
+            Register { username, password } => format!("REGISTER {} {}\n", username, password),
+            Login { username, password } => format!("LOGIN {} {}\n", username, password),
+            Join { channel } => format!("JOIN {}\n", channel),
+            Create { channel } => format!("CREATE {}\n", channel),
+            Say { channel, message } => format!("SAY {} {}\n", channel, message),
+            Channels => String::from("CHANNELS\n"),
+            Report {
+                channel,
+                user,
+                reason,
+            } => format!("REPORT {} {} {}\n", channel, user, reason),
+            Ignore { user } => format!("IGNORE {}\n", user),
+            Unignore { user } => format!("UNIGNORE {}\n", user),
+            Who {
+                channel,
+                page: None,
+            } => format!("WHO {}\n", channel),
+            Who {
+                channel,
+                page: Some((offset, count)),
+            } => format!("WHO {} {} {}\n", channel, offset, count),
+            Limits => String::from("LIMITS\n"),
+            SetName { display } => format!("SETNAME {}\n", display),
+            Pending => String::from("PENDING\n"),
+            Fetch => String::from("FETCH\n"),
+            Alias { old, new } => format!("ALIAS {} {}\n", old, new),
+            Pause => String::from("PAUSE\n"),
+            Resume => String::from("RESUME\n"),
+            Servers => String::from("SERVERS\n"),
+            Defed { addr } => format!("DEFED {}\n", addr),
+            Set { key, value } => format!("SET {} {}\n", key, value),
+            Get { key } => format!("GET {}\n", key),
+            Info { channel } => format!("INFO {}\n", channel),
+            Mode {
+                channel,
+                mode,
+                arg: None,
+            } => format!("MODE {} {}\n", channel, mode),
+            Mode {
+                channel,
+                mode,
+                arg: Some(arg),
+            } => format!("MODE {} {} {}\n", channel, mode, arg),
+            FedPingTest { addr } => format!("FEDPINGTEST {}\n", addr),
+            Invite { channel, user } => format!("INVITE {} {}\n", channel, user),
+            JoinAll { channels } => format!("JOINALL {}\n", channels),
+            Motd => String::from("MOTD\n"),
+            ChanStats { channel } => format!("CHANSTATS {}\n", channel),
+            Profile { user } => format!("PROFILE {}\n", user),
+            Remove { channel, user } => format!("REMOVE {} {}\n", channel, user),
+            Snapshot { offset } => format!("SNAPSHOT {}\n", offset),
+            Migrate { user, addr } => format!("MIGRATE {} {}\n", user, addr),
+            Modes { channel } => format!("MODES {}\n", channel),
+            CancelJoin { channel } => format!("CANCEL JOIN {}\n", channel),
+            Capacity => String::from("CAPACITY\n"),
+            Reply {
+                channel,
+                parent,
+                message,
+            } => format!("REPLY {} {} {}\n", channel, parent, message),
+            InfoAll { channels } => format!("INFOALL {}\n", channels),
+            Reports => String::from("REPORTS\n"),
+        }
+    }
+}
+
+impl ServerRequest<'_> {
+    /// The line, newline included, that parses back into this request.
+    pub fn to_wire(&self) -> String {
+        use ServerRequest::*;
+
+        match self {
+            Out { addr: None } => String::from("FEDOUT\n"),
+            Out { addr: Some(addr) } => format!("FEDOUT {}\n", addr),
+            Reject { reason } => format!("FEDREJECT {}\n", reason),
+            Confirm => String::from("FEDCONFIRM\n"),
+            Channels { channels } => format!("FEDCHANNELS {}\n", channels),
+            Sync { channels } => format!("FEDSYNC {}\n", channels),
+            New { channel } => format!("FEDNEW {}\n", channel),
+            Join { user, channel } => format!("FEDJOIN {} {}\n", user, channel),
+            Presence { user, channel } => format!("FEDPRESENCE {} {}\n", user, channel),
+            Leave { user, channel } => format!("FEDLEAVE {} {}\n", user, channel),
+            Bye => String::from("FEDBYE\n"),
+            Ping { token } => format!("FEDPING {}\n", token),
+            Pong { token } => format!("FEDPONG {}\n", token),
+            Say {
+                user,
+                channel,
+                parent: None,
+                msg,
+            } => format!("FEDSAY {} {} {}\n", user, channel, msg),
+            Say {
+                user,
+                channel,
+                parent: Some(parent),
+                msg,
+            } => format!("FEDREPLY {} {} {} {}\n", user, channel, parent, msg),
+            Recv {
+                to_user,
+                from_user,
+                channel,
+                parent: None,
+                msg,
+            } => format!("FEDRECV {} {} {} {}\n", to_user, from_user, channel, msg),
+            Recv {
+                to_user,
+                from_user,
+                channel,
+                parent: Some(parent),
+                msg,
+            } => format!(
+                "FEDRECVREPLY {} {} {} {} {}\n",
+                to_user, from_user, channel, parent, msg
+            ),
+            Result(ServerResult::Join {
+                user,
+                channel,
+                status,
+            }) => format!("FEDRESULT {} JOIN {} {}\n", user, channel, status),
+            Result(ServerResult::Say {
+                user,
+                channel,
+                status,
+                msg,
+            }) => format!("FEDRESULT {} SAY {} {} {}\n", user, channel, status, msg),
+        }
+    }
+}
+
+impl ClientRequest<'_> {
+    /// Whether the request changes anything the server keeps, beyond the connection it's made on.
+    /// Logging in only starts a session, and pausing only holds back what the connection is sent.
+    pub fn is_write(&self) -> bool {
+        use ClientRequest::*;
+
+        match self {
+            Register { .. }
+            | Create { .. }
+            | Join { .. }
+            | JoinAll { .. }
+            | CancelJoin { .. }
+            | Say { .. }
+            | Reply { .. }
+            | Report { .. }
+            | Ignore { .. }
+            | Unignore { .. }
+            | SetName { .. }
+            | Fetch
+            | Alias { .. }
+            | Defed { .. }
+            | Set { .. }
+            | Mode { .. }
+            | Invite { .. }
+            | Remove { .. }
+            | Migrate { .. } => true,
+            Login { .. }
+            | Channels
+            | Who { .. }
+            | Limits
+            | Pending
+            | Pause
+            | Resume
+            | Servers
+            | Get { .. }
+            | Info { .. }
+            | FedPingTest { .. }
+            | Motd
+            | ChanStats { .. }
+            | Profile { .. }
+            | Snapshot { .. }
+            | Modes { .. }
+            | Capacity
+            | InfoAll { .. }
+            | Reports => false,
+        }
+    }
+
+    /// The request's opcode and arguments in the binary framing.
+    fn opcode(&self) -> (u8, Vec<Cow<'_, str>>) {
+        use ClientRequest::*;
+
+        match *self {
+            Register { username, password } => (1, vec![username.into(), password.into()]),
+            Login { username, password } => (2, vec![username.into(), password.into()]),
+            Join { channel } => (3, vec![channel.into()]),
+            Create { channel } => (4, vec![channel.into()]),
+            Say { channel, message } => (5, vec![channel.into(), message.into()]),
+            Channels => (6, vec![]),
+            Report {
+                channel,
+                user,
+                reason,
+            } => (7, vec![channel.into(), user.into(), reason.into()]),
+            Ignore { user } => (8, vec![user.into()]),
+            Unignore { user } => (9, vec![user.into()]),
+            Who {
+                channel,
+                page: None,
+            } => (10, vec![channel.into()]),
+            Who {
+                channel,
+                page: Some((offset, count)),
+            } => (
+                10,
+                vec![
+                    channel.into(),
+                    offset.to_string().into(),
+                    count.to_string().into(),
+                ],
+            ),
+            Limits => (11, vec![]),
+            SetName { display } => (12, vec![display.into()]),
+            Pending => (13, vec![]),
+            Fetch => (14, vec![]),
+            Alias { old, new } => (15, vec![old.into(), new.into()]),
+            Pause => (16, vec![]),
+            Resume => (17, vec![]),
+            Servers => (18, vec![]),
+            Defed { addr } => (19, vec![addr.into()]),
+            Set { key, value } => (20, vec![key.into(), value.into()]),
+            Get { key } => (21, vec![key.into()]),
+            Info { channel } => (22, vec![channel.into()]),
+            Mode { channel, mode, arg } => {
+                let mut args = vec![channel.into(), mode.into()];
+                args.extend(arg.map(Cow::from));
+                (23, args)
+            }
+            FedPingTest { addr } => (24, vec![addr.into()]),
+            Invite { channel, user } => (25, vec![channel.into(), user.into()]),
+            JoinAll { channels } => (26, vec![channels.into()]),
+            Motd => (27, vec![]),
+            ChanStats { channel } => (28, vec![channel.into()]),
+            Profile { user } => (29, vec![user.into()]),
+            Remove { channel, user } => (30, vec![channel.into(), user.into()]),
+            Snapshot { offset } => (31, vec![offset.to_string().into()]),
+            Migrate { user, addr } => (32, vec![user.into(), addr.into()]),
+            Modes { channel } => (33, vec![channel.into()]),
+            CancelJoin { channel } => (34, vec![channel.into()]),
+            Capacity => (35, vec![]),
+            Reply {
+                channel,
+                parent,
+                message,
+            } => (
+                36,
+                vec![channel.into(), parent.to_string().into(), message.into()],
+            ),
+            InfoAll { channels } => (37, vec![channels.into()]),
+            Reports => (38, vec![]),
+        }
+    }
+}
+
+/// Encodes a request as a binary frame, length included.
+// Only the fuzz harness writes client requests
+#[allow(dead_code)]
+pub fn encode_client(req: &ClientRequest) -> Vec<u8> {
+    let (opcode, args) = req.opcode();
+    let mut body = vec![opcode];
+    for arg in args {
+        body.extend_from_slice(&(arg.len() as u16).to_be_bytes());
+        body.extend_from_slice(arg.as_bytes());
+    }
+    let mut frame = (body.len() as u32).to_be_bytes().to_vec();
+    frame.append(&mut body);
+    frame
+}
+
+/// Decodes the body of a binary frame, the part after its length. Requests the text protocol
+/// couldn't express, like a channel name with a space in it, are refused, so either way of
+/// asking means exactly the same thing.
+pub fn decode_client(mut body: &[u8]) -> Option<ClientRequest<'_>> {
+    use ClientRequest::*;
+
+    let (&opcode, rest) = body.split_first()?;
+    body = rest;
+    let mut args = Vec::new();
+    while !body.is_empty() {
+        let (len, rest) = body.split_at_checked(2)?;
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        let (arg, rest) = rest.split_at_checked(len)?;
+        let arg = std::str::from_utf8(arg).ok()?;
+        if arg.contains(['\n', '\r']) {
+            return None;
+        }
+        args.push(arg);
+        body = rest;
+    }
+
+    let req = match (opcode, args.as_slice()) {
+        (1, &[username, password]) => Register { username, password },
+        (2, &[username, password]) => Login { username, password },
+        (3, &[channel]) => Join { channel },
+        (4, &[channel]) => Create { channel },
+        (5, &[channel, message]) => Say { channel, message },
+        (6, &[]) => Channels,
+        (7, &[channel, user, reason]) => Report {
+            channel,
+            user,
+            reason,
+        },
+        (8, &[user]) => Ignore { user },
+        (9, &[user]) => Unignore { user },
+        (10, &[channel]) => Who {
+            channel,
+            page: None,
+        },
+        (10, &[channel, offset, count]) => Who {
+            channel,
+            page: Some((offset.parse().ok()?, count.parse().ok()?)),
+        },
+        (11, &[]) => Limits,
+        (12, &[display]) => SetName { display },
+        (13, &[]) => Pending,
+        (14, &[]) => Fetch,
+        (15, &[old, new]) => Alias { old, new },
+        (16, &[]) => Pause,
+        (17, &[]) => Resume,
+        (18, &[]) => Servers,
+        (19, &[addr]) => Defed { addr },
+        (20, &[key, value]) => Set { key, value },
+        (21, &[key]) => Get { key },
+        (22, &[channel]) => Info { channel },
+        (23, &[channel, mode]) => Mode {
+            channel,
+            mode,
+            arg: None,
+        },
+        (23, &[channel, mode, arg]) => Mode {
+            channel,
+            mode,
+            arg: Some(arg),
+        },
+        (24, &[addr]) => FedPingTest { addr },
+        (25, &[channel, user]) => Invite { channel, user },
+        (26, &[channels]) => JoinAll { channels },
+        (27, &[]) => Motd,
+        (28, &[channel]) => ChanStats { channel },
+        (29, &[user]) => Profile { user },
+        (30, &[channel, user]) => Remove { channel, user },
+        (31, &[offset]) => Snapshot {
+            offset: offset.parse().ok()?,
+        },
+        (32, &[user, addr]) => Migrate { user, addr },
+        (33, &[channel]) => Modes { channel },
+        (34, &[channel]) => CancelJoin { channel },
+        (35, &[]) => Capacity,
+        (36, &[channel, parent, message]) => Reply {
+            channel,
+            parent: parent.parse().ok()?,
+            message,
+        },
+        (37, &[channels]) => InfoAll { channels },
+        (38, &[]) => Reports,
+        _ => return None,
+    };
+    let wire = req.to_wire();
+    (parse_client(wire.strip_suffix('\n')?).as_ref() == Some(&req)).then_some(req)
+}
+
+/// The longest username or password `REGISTER` and `LOGIN` accept, in bytes. Anything longer is
+/// no account anyone meant to make, and would otherwise be kept as one. Other requests aren't held
+/// to it, as a name from another server arrives as `name@addr`.
+pub const MAX_TOKEN: usize = 256;
+
+fn two(input: &str) -> Option<(&str, &str)> {
+    input.split_once(' ').filter(|(_, b)| !b.contains(' '))
+}
+
+fn credentials(input: &str) -> Option<(&str, &str)> {
+    two(input).filter(|(a, b)| a.len() <= MAX_TOKEN && b.len() <= MAX_TOKEN)
+}
+
+pub fn parse_client(input: &str) -> Option<ClientRequest<'_>> {
+    use ClientRequest::*;
+
+    let (kind, args) = input.split_once(' ').unwrap_or((input, ""));
+    let req = match kind {
+        "REGISTER" => {
+            let (username, password) = credentials(args)?;
+            Register { username, password }
+        }
+        "LOGIN" => {
+            let (username, password) = credentials(args)?;
+            Login { username, password }
+        }
+        "JOIN" => {
+            if args.contains(' ') {
+                return None;
+            }
+            Join { channel: args }
+        }
+        "CREATE" => {
+            if args.contains(' ') {
+                return None;
+            }
+            Create { channel: args }
+        }
+        "SAY" => {
+            let (channel, message) = args.split_once(' ')?;
+            Say { channel, message }
+        }
+        "CHANNELS" => Channels,
+        "LIMITS" => Limits,
+        "PENDING" => Pending,
+        "FETCH" => Fetch,
+        "PAUSE" => Pause,
+        "RESUME" => Resume,
+        "SERVERS" => Servers,
+        "MOTD" => Motd,
+        "CAPACITY" => Capacity,
+        "REPORTS" => Reports,
+        "REPLY" => {
+            let (channel, rest) = args.split_once(' ')?;
+            let (parent, message) = rest.split_once(' ')?;
+            Reply {
+                channel,
+                parent: parent.parse().ok()?,
+                message,
+            }
+        }
+        "SNAPSHOT" => Snapshot {
+            offset: if args.is_empty() {
+                0
+            } else {
+                args.parse().ok()?
+            },
+        },
+        "DEFED" => {
+            if args.is_empty() || args.contains(' ') {
+                return None;
+            }
+            Defed { addr: args }
+        }
+        "SET" => {
+            let (key, value) = args.split_once(' ').unwrap_or((args, ""));
+            if key.is_empty() {
+                return None;
+            }
+            Set { key, value }
+        }
+        "FEDPINGTEST" => {
+            if args.is_empty() || args.contains(' ') {
+                return None;
+            }
+            FedPingTest { addr: args }
+        }
+        "JOINALL" => {
+            if args.is_empty() || args.contains(' ') {
+                return None;
+            }
+            JoinAll { channels: args }
+        }
+        "INFOALL" => {
+            if args.is_empty() || args.contains(' ') {
+                return None;
+            }
+            InfoAll { channels: args }
+        }
+        "INVITE" => {
+            let (channel, user) = two(args)?;
+            Invite { channel, user }
+        }
+        "REMOVE" => {
+            let (channel, user) = two(args)?;
+            Remove { channel, user }
+        }
+        "MIGRATE" => {
+            let (user, addr) = two(args)?;
+            Migrate { user, addr }
+        }
+        "INFO" => {
+            if args.is_empty() || args.contains(' ') {
+                return None;
+            }
+            Info { channel: args }
+        }
+        "CANCEL" => match two(args)? {
+            ("JOIN", channel) if !channel.is_empty() => CancelJoin { channel },
+            _ => return None,
+        },
+        "MODES" => {
+            if args.is_empty() || args.contains(' ') {
+                return None;
+            }
+            Modes { channel: args }
+        }
+        "PROFILE" => {
+            if args.is_empty() || args.contains(' ') {
+                return None;
+            }
+            Profile { user: args }
+        }
+        "CHANSTATS" => {
+            if args.is_empty() || args.contains(' ') {
+                return None;
+            }
+            ChanStats { channel: args }
+        }
+        "MODE" => match args.split(' ').collect::<Vec<_>>()[..] {
+            [channel, mode] => Mode {
+                channel,
+                mode,
+                arg: None,
+            },
+            [channel, mode, arg] => Mode {
+                channel,
+                mode,
+                arg: Some(arg),
+            },
+            _ => return None,
+        },
+        "GET" => {
+            if args.is_empty() || args.contains(' ') {
+                return None;
+            }
+            Get { key: args }
+        }
+        "ALIAS" => {
+            let (old, new) = two(args)?;
+            Alias { old, new }
+        }
+        "SETNAME" => {
+            if args.is_empty() || args.contains(' ') {
+                return None;
+            }
+            SetName { display: args }
+        }
+        "REPORT" => {
+            let (channel, args) = args.split_once(' ')?;
+            let (user, reason) = args.split_once(' ')?;
+            Report {
+                channel,
+                user,
+                reason,
+            }
+        }
+        "IGNORE" => {
+            if args.contains(' ') {
+                return None;
+            }
+            Ignore { user: args }
+        }
+        "UNIGNORE" => {
+            if args.contains(' ') {
+                return None;
+            }
+            Unignore { user: args }
+        }
+        "WHO" => match args.split(' ').collect::<Vec<_>>()[..] {
+            [channel] => Who {
+                channel,
+                page: None,
+            },
+            [channel, offset, count] => Who {
+                channel,
+                page: Some((offset.parse().ok()?, count.parse().ok()?)),
+            },
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(req)
+}
+
+pub fn parse_server(input: &str) -> Option<ServerRequest<'_>> {
+    use ServerRequest::*;
+
+    let (kind, args) = input.split_once(' ').unwrap_or((input, ""));
+    let req = match kind {
+        "FEDOUT" => {
+            if args.contains(' ') {
+                return None;
+            }
+            Out {
+                addr: (!args.is_empty()).then_some(args),
+            }
+        }
+        "FEDREJECT" => Reject { reason: args },
+        "FEDCONFIRM" => Confirm,
+        "FEDBYE" => Bye,
+        "FEDPING" => {
+            if args.is_empty() || args.contains(' ') {
+                return None;
+            }
+            Ping { token: args }
+        }
+        "FEDPONG" => {
+            if args.is_empty() || args.contains(' ') {
+                return None;
+            }
+            Pong { token: args }
+        }
+        "FEDCHANNELS" => Channels { channels: args },
+        "FEDSYNC" => Sync { channels: args },
+        "FEDNEW" => {
+            if args.contains(' ') {
+                return None;
+            }
+            New { channel: args }
+        }
+        "FEDJOIN" => {
+            let (user, channel) = two(args)?;
+            Join { user, channel }
+        }
+        "FEDPRESENCE" => {
+            let (user, channel) = two(args)?;
+            Presence { user, channel }
+        }
+        "FEDLEAVE" => {
+            let (user, channel) = two(args)?;
+            Leave { user, channel }
+        }
+        "FEDSAY" => {
+            let (user, args) = args.split_once(' ')?;
+            let (channel, msg) = args.split_once(' ')?;
+            Say {
+                user,
+                channel,
+                parent: None,
+                msg,
+            }
+        }
+        "FEDREPLY" => {
+            let (user, args) = args.split_once(' ')?;
+            let (channel, args) = args.split_once(' ')?;
+            let (parent, msg) = args.split_once(' ')?;
+            Say {
+                user,
+                channel,
+                parent: Some(parent.parse().ok()?),
+                msg,
+            }
+        }
+        "FEDRECV" => {
+            let (to_user, args) = args.split_once(' ')?;
+            let (from_user, args) = args.split_once(' ')?;
+            let (channel, msg) = args.split_once(' ')?;
+            Recv {
+                to_user,
+                from_user,
+                channel,
+                parent: None,
+                msg,
+            }
+        }
+        "FEDRECVREPLY" => {
+            let (to_user, args) = args.split_once(' ')?;
+            let (from_user, args) = args.split_once(' ')?;
+            let (channel, args) = args.split_once(' ')?;
+            let (parent, msg) = args.split_once(' ')?;
+            Recv {
+                to_user,
+                from_user,
+                channel,
+                parent: Some(parent.parse().ok()?),
+                msg,
+            }
+        }
+        "FEDRESULT" => {
+            let (user, args) = args.split_once(' ')?;
+            let (kind, args) = args.split_once(' ')?;
+            let (channel, args) = args.split_once(' ')?;
+            match kind {
+                "JOIN" => {
+                    if !matches!(args, "0" | "1") {
+                        return None;
+                    }
+                    Result(ServerResult::Join {
+                        user,
+                        channel,
+                        status: args,
+                    })
+                }
+                "SAY" => {
+                    let (status, msg) = args.split_once(' ')?;
+                    if !matches!(status, "0" | "1") {
+                        return None;
+                    }
+                    Result(ServerResult::Say {
+                        user,
+                        channel,
+                        status,
+                        msg,
+                    })
+                }
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    Some(req)
+}
+
+pub fn parse(input: &str) -> Option<Request<'_>> {
+    parse_client(input)
+        .map(Request::Client)
+        .or_else(|| parse_server(input).map(Request::Server))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// At least one of every request, with each of the shapes some of them take.
+    fn requests() -> Vec<ClientRequest<'static>> {
+        use ClientRequest::*;
+
+        vec![
+            Register {
+                username: "u",
+                password: "p",
+            },
+            Login {
+                username: "u",
+                password: "p",
+            },
+            Join { channel: "c" },
+            Create { channel: "c" },
+            Say {
+                channel: "c",
+                message: "hello there",
+            },
+            Channels,
+            Report {
+                channel: "c",
+                user: "v",
+                reason: "said rude things",
+            },
+            Ignore { user: "v" },
+            Unignore { user: "v" },
+            Who {
+                channel: "c",
+                page: None,
+            },
+            Who {
+                channel: "c",
+                page: Some((10, 5)),
+            },
+            Limits,
+            SetName { display: "w" },
+            Pending,
+            Fetch,
+            Alias { old: "c", new: "d" },
+            Pause,
+            Resume,
+            Servers,
+            Defed {
+                addr: "127.0.0.1:1",
+            },
+            Set {
+                key: "mentions",
+                value: "on",
+            },
+            Set {
+                key: "mentions",
+                value: "",
+            },
+            Get { key: "mentions" },
+            Info { channel: "c" },
+            Mode {
+                channel: "c",
+                mode: "+p",
+                arg: None,
+            },
+            Mode {
+                channel: "c",
+                mode: "+h",
+                arg: Some("5"),
+            },
+            FedPingTest {
+                addr: "127.0.0.1:1",
+            },
+            Invite {
+                channel: "c",
+                user: "v",
+            },
+            JoinAll { channels: "c,d" },
+            Motd,
+            ChanStats { channel: "c" },
+            Profile { user: "v" },
+            Remove {
+                channel: "c",
+                user: "v",
+            },
+            Snapshot { offset: 20 },
+            Migrate {
+                user: "v",
+                addr: "127.0.0.1:1",
+            },
+            Modes { channel: "c" },
+            CancelJoin { channel: "c" },
+            Capacity,
+            Reply {
+                channel: "c",
+                parent: 7,
+                message: "me too",
+            },
+            InfoAll { channels: "c,d" },
+            Reports,
+        ]
+    }
+
+    #[test]
+    fn every_opcode_is_tested() {
+        let mut opcodes: Vec<u8> = requests().iter().map(|req| req.opcode().0).collect();
+        opcodes.dedup();
+        assert_eq!(opcodes, (1..=38).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn binary_round_trips_to_the_text_request() {
+        for req in requests() {
+            let frame = encode_client(&req);
+            let (len, body) = frame.split_at(4);
+            assert_eq!(
+                u32::from_be_bytes(len.try_into().unwrap()) as usize,
+                body.len()
+            );
+            assert_eq!(decode_client(body).as_ref(), Some(&req));
+
+            let wire = req.to_wire();
+            let line = wire.strip_suffix('\n').unwrap();
+            assert_eq!(parse_client(line).as_ref(), Some(&req), "{}", line);
+        }
+    }
+
+    #[test]
+    fn binary_refuses_what_text_cant_say() {
+        let join = ClientRequest::Join { channel: "c d" };
+        assert_eq!(decode_client(&encode_client(&join)[4..]), None);
+    }
+}
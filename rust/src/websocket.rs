@@ -0,0 +1,305 @@
+//! Just enough of WebSockets (RFC 6455) for browsers to speak the text protocol: the opening
+//! handshake's headers and accept key, and reading and writing single frames.
+
+pub const CONTINUATION: u8 = 0x0;
+pub const TEXT: u8 = 0x1;
+pub const CLOSE: u8 = 0x8;
+pub const PING: u8 = 0x9;
+pub const PONG: u8 = 0xa;
+
+/// Whether a connection's first line is an HTTP request, which is how a WebSocket opens.
+pub fn is_upgrade(line: &str) -> bool {
+    line.starts_with("GET ") && line.ends_with(" HTTP/1.1")
+}
+
+/// The headers of an opening handshake that the server cares about.
+#[derive(Default)]
+pub struct Handshake {
+    key: Option<String>,
+    upgrade: bool,
+    connection: bool,
+}
+
+impl Handshake {
+    /// Takes note of one header line.
+    pub fn header(&mut self, line: &str) {
+        let Some((name, value)) = line.split_once(':') else {
+            return;
+        };
+        let value = value.trim();
+        // Both `Upgrade` and `Connection` are lists, of which ours need only be one
+        let lists = |token| {
+            value
+                .split(',')
+                .any(|v| v.trim().eq_ignore_ascii_case(token))
+        };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "sec-websocket-key" => self.key = Some(value.to_string()),
+            "upgrade" => self.upgrade = lists("websocket"),
+            "connection" => self.connection = lists("upgrade"),
+            _ => {}
+        }
+    }
+
+    /// The client's `Sec-WebSocket-Key`, if the headers asked to upgrade to a WebSocket at all.
+    pub fn key(self) -> Option<String> {
+        self.key.filter(|_| self.upgrade && self.connection)
+    }
+}
+
+/// The `Sec-WebSocket-Accept` answering a client's `Sec-WebSocket-Key`.
+pub fn accept(key: &str) -> String {
+    const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+    base64(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+pub struct Frame {
+    /// Whether this is the last frame of its message
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Reads the frame at the start of `buf`, giving it with how many bytes it took up, or `None`
+/// if it hasn't all arrived yet. Clients must mask what they send, and no frame may be longer
+/// than `max`.
+pub fn parse_frame(buf: &[u8], max: usize) -> Result<Option<(Frame, usize)>, &'static str> {
+    let [first, second, ..] = *buf else {
+        return Ok(None);
+    };
+    if second & 0x80 == 0 {
+        return Err("unmasked frame");
+    }
+    let (len, mut at) = match second & 0x7f {
+        126 => match buf.get(2..4) {
+            Some(len) => (u16::from_be_bytes(len.try_into().unwrap()) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match buf.get(2..10) {
+            Some(len) => (u64::from_be_bytes(len.try_into().unwrap()), 10),
+            None => return Ok(None),
+        },
+        len => (len as u64, 2),
+    };
+    if len > max as u64 {
+        return Err("frame too large");
+    }
+    let len = len as usize;
+    let Some(mask) = buf.get(at..at + 4) else {
+        return Ok(None);
+    };
+    at += 4;
+    let Some(payload) = buf.get(at..at + len) else {
+        return Ok(None);
+    };
+    let payload = payload
+        .iter()
+        .zip(mask.iter().cycle())
+        .map(|(b, m)| b ^ m)
+        .collect();
+    let frame = Frame {
+        fin: first & 0x80 != 0,
+        opcode: first & 0x0f,
+        payload,
+    };
+    Ok(Some((frame, at + len)))
+}
+
+/// A whole, unmasked frame, as servers send them.
+pub fn encode(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (out, word) in digest.chunks_mut(4).zip(h) {
+        out.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut s = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | ((b as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_the_rfc_sample_key() {
+        // RFC 6455 section 1.3
+        assert_eq!(
+            accept("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn sha1_and_base64_of_known_values() {
+        let hex: String = sha1(b"abc").iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hex, "a9993e364706816aba3e25717850c26c9cd0d89d");
+        // Long enough to need a second block
+        let hex: String = sha1(&[b'a'; 64])
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(hex, "0098ba824b5c16427bd7a1122a5a442a25ec644d");
+        for (data, encoded) in [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v")] {
+            assert_eq!(base64(data.as_bytes()), encoded);
+        }
+    }
+
+    fn handshake(headers: &[&str]) -> Option<String> {
+        let mut handshake = Handshake::default();
+        for header in headers {
+            handshake.header(header);
+        }
+        handshake.key()
+    }
+
+    #[test]
+    fn only_a_websocket_upgrade_has_a_key() {
+        let key = "Sec-WebSocket-Key: k";
+        assert_eq!(
+            handshake(&["Upgrade: websocket", "Connection: Upgrade", key]).as_deref(),
+            Some("k")
+        );
+        // As Firefox sends it
+        assert_eq!(
+            handshake(&["upgrade: WebSocket", "Connection: keep-alive, Upgrade", key]).as_deref(),
+            Some("k")
+        );
+        assert_eq!(handshake(&["Connection: Upgrade", key]), None);
+        assert_eq!(
+            handshake(&["Upgrade: h2c", "Connection: Upgrade", key]),
+            None
+        );
+        assert_eq!(handshake(&["Upgrade: websocket", key]), None);
+        assert_eq!(
+            handshake(&["Upgrade: websocket", "Connection: Upgrade"]),
+            None
+        );
+    }
+
+    /// A frame as a client would send it, masked.
+    fn masked(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = encode(opcode, payload);
+        let at = frame.len() - payload.len();
+        frame[1] |= 0x80;
+        for (i, b) in frame[at..].iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+        frame.splice(at..at, mask);
+        frame
+    }
+
+    #[test]
+    fn masked_frames_parse_at_every_length() {
+        for len in [0, 5, 125, 126, 0xffff, 0x10000] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut buf = masked(TEXT, &payload);
+            let size = buf.len();
+            buf.extend_from_slice(b"next");
+            let (frame, used) = parse_frame(&buf, 0x10000).unwrap().unwrap();
+            assert_eq!(used, size);
+            assert!(frame.fin);
+            assert_eq!(frame.opcode, TEXT);
+            assert_eq!(frame.payload, payload);
+            // Nothing until it's all arrived
+            assert!(parse_frame(&buf[..size - 1], 0x10000).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn rfc_sample_frame_parses() {
+        // RFC 6455 section 5.7, a masked "Hello"
+        let buf = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let (frame, used) = parse_frame(&buf, 125).unwrap().unwrap();
+        assert_eq!((used, frame.payload), (buf.len(), b"Hello".to_vec()));
+        assert_eq!(
+            encode(TEXT, b"Hello"),
+            [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]
+        );
+    }
+
+    #[test]
+    fn unmasked_and_oversized_frames_are_refused() {
+        assert!(parse_frame(&encode(TEXT, b"Hello"), 125).is_err());
+        assert!(parse_frame(&masked(TEXT, &[0; 126]), 125).is_err());
+        // Refused from the length alone, before the rest arrives
+        assert!(parse_frame(&masked(TEXT, &[0; 126])[..4], 125).is_err());
+    }
+}
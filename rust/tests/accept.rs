@@ -0,0 +1,42 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// A server that runs out of file descriptors keeps going, and takes connections again once
+/// some are freed.
+#[test]
+fn accepting_survives_running_out_of_descriptors() {
+    // Only a few more than the server needs to start with, so connections use up the rest
+    let mut server = Command::new("sh")
+        .arg("-c")
+        .arg("ulimit -n 16 && exec \"$0\" 0")
+        .arg(env!("CARGO_BIN_EXE_chat_server"))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut addr = String::new();
+    BufReader::new(server.stdout.take().unwrap())
+        .read_line(&mut addr)
+        .unwrap();
+    let addr = addr.trim();
+
+    // Those past the limit wait to be accepted while the server fails to
+    let crowd: Vec<TcpStream> = (0..16).map(|_| TcpStream::connect(addr).unwrap()).collect();
+    std::thread::sleep(Duration::from_millis(500));
+    assert!(server.try_wait().unwrap().is_none(), "the server exited");
+    drop(crowd);
+
+    let stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    (&stream).write_all(b"REGISTER u p\n").unwrap();
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply).unwrap();
+    assert_eq!(reply, "RESULT REGISTER 1\n");
+
+    let _ = server.kill();
+    let _ = server.wait();
+}
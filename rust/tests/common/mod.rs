@@ -0,0 +1,166 @@
+//! Runs real servers federated with each other, for tests that need more than one.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// How long anything a test waits on can take before it fails.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Servers that all list each other as peers, and are federated by the time it's returned.
+/// They're stopped when it's dropped.
+pub struct Mesh {
+    servers: Vec<Child>,
+    peer_files: Vec<PathBuf>,
+    /// The address each server listens on, in the order they were started
+    pub addrs: Vec<String>,
+}
+
+impl Mesh {
+    pub fn start(count: usize) -> Self {
+        Mesh::with_options(count, &[])
+    }
+
+    /// Like `start`, with every server given these options too.
+    pub fn with_options(count: usize, options: &[&str]) -> Self {
+        let mut mesh = Mesh {
+            servers: Vec::new(),
+            peer_files: Vec::new(),
+            addrs: Vec::new(),
+        };
+        for _ in 0..count {
+            // Empty to begin with, as the others' addresses aren't known until they're up
+            let peer_file = scratch_file();
+            std::fs::write(&peer_file, "").unwrap();
+            let mut server = Command::new(env!("CARGO_BIN_EXE_chat_server"))
+                .arg("0")
+                .arg(&peer_file)
+                .args(options)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap();
+            let mut addr = String::new();
+            BufReader::new(server.stdout.take().unwrap())
+                .read_line(&mut addr)
+                .unwrap();
+            mesh.addrs.push(addr.trim().to_string());
+            mesh.servers.push(server);
+            mesh.peer_files.push(peer_file);
+        }
+
+        // Each dials the others it's told about on SIGHUP
+        for (n, path) in mesh.peer_files.iter().enumerate() {
+            let others: Vec<&str> = (0..count)
+                .filter(|&other| other != n)
+                .map(|other| mesh.addrs[other].as_str())
+                .collect();
+            std::fs::write(path, others.join("\n")).unwrap();
+        }
+        for server in &mesh.servers {
+            let status = Command::new("kill")
+                .arg("-HUP")
+                .arg(server.id().to_string())
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+        for n in 0..count {
+            mesh.await_peers(n, count - 1);
+        }
+        mesh
+    }
+
+    /// Connects a client to the `n`th server.
+    pub fn client(&self, n: usize) -> Client {
+        Client::connect(&self.addrs[n])
+    }
+
+    /// Waits until the `n`th server has finished the handshake with as many peers.
+    fn await_peers(&self, n: usize, peers: usize) {
+        let started = Instant::now();
+        let mut client = self.client(n);
+        loop {
+            client.send("SERVERS\n");
+            let line = client.line();
+            let listed = line.strip_prefix("RESULT SERVERS").map_or(0, |addrs| {
+                addrs.split(", ").filter(|a| !a.trim().is_empty()).count()
+            });
+            if listed == peers {
+                return;
+            }
+            assert!(
+                started.elapsed() < TIMEOUT,
+                "{} only federated with {} of {} peers",
+                self.addrs[n],
+                listed,
+                peers
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+impl Drop for Mesh {
+    fn drop(&mut self) {
+        for server in &mut self.servers {
+            let _ = server.kill();
+            let _ = server.wait();
+        }
+        for path in &self.peer_files {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// A connection speaking the text protocol.
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    pub fn connect(addr: &str) -> Self {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        Client {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    pub fn send(&mut self, lines: &str) {
+        assert!(self.try_send(lines), "the server hung up");
+    }
+
+    /// Like `send`, for when the server may have hung up. Gives whether it hadn't.
+    pub fn try_send(&mut self, lines: &str) -> bool {
+        self.writer.write_all(lines.as_bytes()).is_ok()
+    }
+
+    /// The next line from the server, without its newline.
+    pub fn line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with('\n'), "the server hung up after {:?}", line);
+        line.pop();
+        line
+    }
+
+    /// Checks the server sends each of these lines next, in order.
+    pub fn expect(&mut self, lines: &str) {
+        for expected in lines.lines() {
+            assert_eq!(self.line(), expected);
+        }
+    }
+}
+
+/// A path no other test is using.
+fn scratch_file() -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let n = NEXT.fetch_add(1, Ordering::SeqCst);
+    std::env::temp_dir().join(format!("chat_server-{}-{}.peers", std::process::id(), n))
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use common::Mesh;
+
+#[test]
+fn message_reaches_member_on_another_server() {
+    let mesh = Mesh::start(2);
+    let (a, b) = (&mesh.addrs[0], &mesh.addrs[1]);
+
+    let mut u = mesh.client(0);
+    u.send("REGISTER u p\nLOGIN u p\nCREATE c\nJOIN c\n");
+    u.expect("RESULT REGISTER 1\nRESULT LOGIN 1\nRESULT CREATE c 1\nRESULT JOIN c 1\n");
+
+    let mut v = mesh.client(1);
+    v.send(&format!("REGISTER v p\nLOGIN v p\nJOIN c:{}\n", a));
+    v.expect(&format!(
+        "RESULT REGISTER 1\nRESULT LOGIN 1\nRESULT JOIN c:{} 1\n",
+        a
+    ));
+
+    u.send("SAY c hi\n");
+    u.expect("RESULT SAY c 1\nRECV u c hi\n");
+    v.expect(&format!("RECV u c:{} hi\n", a));
+
+    // And back the other way, through the same link
+    v.send(&format!("SAY c:{} hello\n", a));
+    v.expect(&format!(
+        "RECV v@{} c:{} hello\nRESULT SAY c:{} 1\n",
+        b, a, a
+    ));
+    u.expect(&format!("RECV v@{} c hello\n", b));
+}
+
+#[test]
+fn longest_name_joins_a_channel_on_another_server() {
+    let mesh = Mesh::start(2);
+    let a = &mesh.addrs[0];
+
+    let mut u = mesh.client(0);
+    u.send("REGISTER u p\nLOGIN u p\nCREATE c\n");
+    u.expect("RESULT REGISTER 1\nRESULT LOGIN 1\nRESULT CREATE c 1\n");
+
+    // As long as REGISTER takes, and longer than that once it's `name@addr` on the link
+    let name = "v".repeat(256);
+    let mut v = mesh.client(1);
+    v.send(&format!(
+        "REGISTER {0} p\nLOGIN {0} p\nJOIN c:{1}\n",
+        name, a
+    ));
+    v.expect(&format!(
+        "RESULT REGISTER 1\nRESULT LOGIN 1\nRESULT JOIN c:{} 1\n",
+        a
+    ));
+}
+
+#[test]
+fn every_server_federates_with_every_other() {
+    let mesh = Mesh::start(3);
+    for n in 0..3 {
+        let mut client = mesh.client(n);
+        client.send("SERVERS\n");
+        let mut others: Vec<&str> = (0..3)
+            .filter(|&other| other != n)
+            .map(|other| mesh.addrs[other].as_str())
+            .collect();
+        others.sort_unstable();
+        client.expect(&format!("RESULT SERVERS {}\n", others.join(", ")));
+    }
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use common::{Client, Mesh};
+use std::thread;
+
+const ROUNDS: usize = 40;
+const PER_ROUND: usize = 50;
+
+/// Two members of the same channel, on the first server.
+fn members(mesh: &Mesh) -> Vec<Client> {
+    let mut clients = Vec::new();
+    for name in ["u", "v"] {
+        let mut client = mesh.client(0);
+        client.send(&format!(
+            "REGISTER {0} p\nLOGIN {0} p\nCREATE c\nJOIN c\n",
+            name
+        ));
+        client.expect("RESULT REGISTER 1\nRESULT LOGIN 1\n");
+        // Whoever is second finds the channel already made
+        client.line();
+        client.expect("RESULT JOIN c 1\n");
+        clients.push(client);
+    }
+    clients
+}
+
+/// Says many messages, reading as it goes. Gives whether it heard everything both members said,
+/// rather than being told it lagged first.
+fn talk(mut client: Client) -> bool {
+    let (mut results, mut heard) = (0, 0);
+    for round in 1..=ROUNDS {
+        if !client.try_send(&"SAY c hi\n".repeat(PER_ROUND)) {
+            return false;
+        }
+        // Reading as we go, so neither of us stops the server writing to us
+        while results < round * PER_ROUND {
+            match client.line().as_str() {
+                "RESULT SAY c 1" => results += 1,
+                "RESULT ERROR lagged" => return false,
+                line if line.starts_with("RECV ") => heard += 1,
+                line => panic!("unexpected {:?}", line),
+            }
+        }
+    }
+    // Both of us hear everything either of us said
+    while heard < 2 * ROUNDS * PER_ROUND {
+        match client.line().as_str() {
+            "RESULT ERROR lagged" => return false,
+            line => assert!(line.starts_with("RECV "), "unexpected {:?}", line),
+        }
+        heard += 1;
+    }
+    true
+}
+
+/// Two members saying many messages to each other at once, each answered and heard by both.
+#[test]
+fn members_talking_over_each_other_hear_everything() {
+    let mesh = Mesh::start(1);
+    let talkers: Vec<_> = members(&mesh)
+        .into_iter()
+        .map(|client| thread::spawn(move || talk(client)))
+        .collect();
+    for talker in talkers {
+        assert!(talker.join().unwrap());
+    }
+}
+
+/// The same with queues small enough to fill up. Neither member's connection waits on the
+/// other's, so neither stalls: each hears everything or is told it lagged, well within the time
+/// the test waits on a line, and the server carries on serving once they're done.
+#[test]
+fn members_talking_over_each_other_into_full_queues_never_stall() {
+    let mesh = Mesh::with_options(1, &["--client-queue=4"]);
+    let talkers: Vec<_> = members(&mesh)
+        .into_iter()
+        .map(|client| thread::spawn(move || talk(client)))
+        .collect();
+    for talker in talkers {
+        talker.join().unwrap();
+    }
+
+    let mut client = mesh.client(0);
+    client.send("REGISTER w p\nLOGIN w p\nJOIN c\nSAY c still here\n");
+    client.expect("RESULT REGISTER 1\nRESULT LOGIN 1\nRESULT JOIN c 1\n");
+    client.expect("RESULT SAY c 1\nRECV w c still here\n");
+}
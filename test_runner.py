@@ -3,6 +3,10 @@ import json
 import socket
 import subprocess
 import os
+import re
+import signal
+import tempfile
+import time
 
 
 def receive_equals(left, right):
@@ -22,47 +26,287 @@ def receive_equals(left, right):
     return leftset == rightset and len(leftset) == length
 
 
-def recv(sock, rcv):
-    msg = b""
+def recv(sock, rcv, ordered):
+    msg = bytearray()
     try:
         while len(msg) < len(rcv):
-            msg += sock.recv(1024)
+            chunk = sock.recv(65536)
+            if not chunk:
+                break
+            msg += chunk
     except socket.timeout:
         return False, "socket timeout"
+    msg = bytes(msg)
 
-    if receive_equals(msg, rcv):
+    if msg == rcv if ordered else receive_equals(msg, rcv):
         return True, "Receives equal!"
     else:
         return False, f"Expected {rcv} Got {msg}"
 
 
-def send_and_recv(sock, snd, rcv):
+def recv_pattern(sock, pattern):
+    msg = bytearray()
+    try:
+        while not msg.endswith(b"\n"):
+            chunk = sock.recv(1)
+            if not chunk:
+                break
+            msg += chunk
+    except socket.timeout:
+        return False, "socket timeout"
+    if re.fullmatch(pattern, msg.decode()):
+        return True, "Receives match!"
+    return False, f"Expected a line matching {pattern!r} Got {bytes(msg)}"
+
+
+def send_and_recv(sock, snd, rcv, ordered=False):
     # @Todo @Exception this could throw ?
     sock.sendall(snd)
-    return recv(sock, rcv)
+    # A null receive means we don't wait for (or read) any reply
+    if rcv is None:
+        return True, "Nothing to receive"
+    return recv(sock, rcv, ordered)
+
+
+def closed(sock, drain=False, last=None):
+    # Draining skips whatever the server writes first, though if "last" is
+    # given it has to be how that ends
+    seen = b""
+    while True:
+        try:
+            data = sock.recv(65536)
+        except socket.timeout:
+            return False, "Connection still open"
+        except ConnectionResetError:
+            return True, "Connection reset"
+        if not data:
+            break
+        if not drain:
+            return False, f"Expected the connection to close Got {data}"
+        seen = (seen + data)[-4096:]
+    if last is not None and not seen.endswith(last):
+        return False, f"Expected {last} before closing Got {seen[-len(last) * 2:]}"
+    return True, "Connection closed"
+
+
+def expand(snd, names, sending=False):
+    # Sends may be a list of strings and {"repeat": str, "times": n} pieces
+    # to keep large payloads readable. {"frame": str} is a binary mode line,
+    # {"request": [opcode, arg, ...]} a binary mode request and {"ws": str} a
+    # WebSocket text message, masked when sent as clients must, or another
+    # kind of frame given its "opcode"
+    if isinstance(snd, str):
+        return substitute(snd, names).encode()
+    if isinstance(snd, dict):
+        if "ws" in snd:
+            # An all zero mask leaves the payload as it is
+            body = expand(snd["ws"], names)
+            kind = 0x80 | snd.get("opcode", 1)
+            mask = 0x80 if sending else 0
+            if len(body) < 126:
+                header = bytes([kind, mask | len(body)])
+            else:
+                header = bytes([kind, mask | 126]) + len(body).to_bytes(2, "big")
+            return header + (b"\0" * 4 if sending else b"") + body
+        if "frame" in snd:
+            body = expand(snd["frame"], names)
+        elif "request" in snd:
+            [opcode, *args] = snd["request"]
+            body = bytes([opcode])
+            for arg in args:
+                arg = expand(arg, names)
+                body += len(arg).to_bytes(2, "big") + arg
+        else:
+            return expand(snd["repeat"], names) * snd["times"]
+        return len(body).to_bytes(4, "big") + body
+    return b"".join(expand(piece, names, sending) for piece in snd)
+
+
+def shutdown(server, expected):
+    server.send_signal(signal.SIGINT)
+    try:
+        out, _ = server.communicate(timeout=expected["within"])
+    except subprocess.TimeoutExpired:
+        return False, f"Server still running {expected['within']}s after SIGINT"
+    if expected["stdout"].encode() not in out:
+        return False, f"Expected {expected['stdout']} in shutdown output Got {out}"
+    return True, "Shut down"
 
 
-def client(test, addr):
-    [_, port] = addr.rsplit(":", 1)
-    with socket.create_connection(("localhost", int(port))) as sock:
-        sock.settimeout(5)
-        for i, [s, r] in enumerate(test["snr"]):
-            ok, msg = send_and_recv(sock, s.encode(), r.encode())
+def substitute(text, names):
+    for name, value in names.items():
+        text = text.replace(name, value)
+    return text
+
+
+def connect(c, ports, peers):
+    # peerN connections are the server dialing out to the Nth mock peer, and
+    # "sN:name" connections go to the Nth server rather than the first
+    if isinstance(c, str) and c.startswith("peer"):
+        sock, _ = peers[int(c[len("peer"):])].accept()
+    elif isinstance(c, str) and re.match(r"s\d+:", c):
+        sock = socket.create_connection(("localhost", int(ports[int(c[1:].split(":")[0])])))
+    else:
+        sock = socket.create_connection(("localhost", int(ports[0])))
+    sock.settimeout(5)
+    return sock
+
+
+def client(test, addrs, server, peers, names):
+    ports = [addr.rsplit(":", 1)[1] for addr in addrs]
+    # $ADDR stands in for the address the server is listening on, and $ADDRN
+    # for the Nth server's. The numbered ones go first so $ADDR can't eat them
+    names = {**names, **{f"$ADDR{n}": a for n, a in enumerate(addrs)}, "$ADDR": addrs[0]}
+    socks = {}
+    start = time.monotonic()
+    try:
+        # Steps are either [send, recv] on the first connection, or
+        # [conn, send, recv] where conn picks the connection to use. A null
+        # send closes the connection, {"sleep": secs} pauses the test,
+        # {"elapsed": secs} checks at least that long has passed since it began,
+        # {"config": text} rewrites $CONFIG, {"signal": name} signals the server and
+        # {"closed": conn} checks the server hung up on that connection, after
+        # anything it wrote first if "drain" is set, which may still have to
+        # end with "last".
+        for i, step in enumerate(test["snr"]):
+            if isinstance(step, dict) and "closed" in step:
+                last = step.get("last")
+                last = None if last is None else expand(last, names)
+                ok, msg = closed(socks[step["closed"]], step.get("drain", False), last)
+                if not ok:
+                    return False, f"Test {test['name']} failed on cmd {i+1}: {msg}"
+                continue
+            if isinstance(step, dict):
+                took = time.monotonic() - start
+                if took < step.get("elapsed", 0):
+                    return False, f"Test {test['name']} failed on cmd {i+1}: only {took:.2f}s passed"
+                if "config" in step:
+                    with open(names["$CONFIG"], "w") as f:
+                        f.write(step["config"])
+                if "signal" in step:
+                    server.send_signal(getattr(signal, step["signal"]))
+                time.sleep(step.get("sleep", 0))
+                continue
+            [c, s, r] = step if len(step) == 3 else [0, *step]
+            if s is None:
+                socks.pop(c).close()
+                continue
+            if c not in socks:
+                socks[c] = connect(c, ports, peers)
+            s = expand(s, names, sending=True)
+            # {"pattern": regex} receives a line matching it, for replies
+            # that vary from run to run
+            if isinstance(r, dict) and "pattern" in r:
+                socks[c].sendall(s)
+                ok, msg = recv_pattern(socks[c], substitute(r["pattern"], names))
+            else:
+                r = None if r is None else expand(r, names)
+                ok, msg = send_and_recv(socks[c], s, r,
+                                        test.get("ordered", False))
             if not ok:
                 return False, f"Test {test['name']} failed on cmd {i+1}: {msg}"
+        # Connections stay open while shutting down so they can hold it up
+        if "shutdown" in test:
+            ok, msg = shutdown(server, test["shutdown"])
+            if not ok:
+                return False, f"Test {test['name']} failed on shutdown: {msg}"
+    finally:
+        for sock in socks.values():
+            sock.close()
     return True, "Test Passed!"
 
 
+def check_file(path, patterns):
+    # Each line the server wrote must fully match the pattern in its place
+    with open(path) as f:
+        lines = f.read().splitlines()
+    if len(lines) != len(patterns):
+        return False, f"Expected {len(patterns)} lines in the file Got {lines}"
+    for line, pattern in zip(lines, patterns):
+        if not re.fullmatch(pattern, line):
+            return False, f"Expected a line matching {pattern} Got {line}"
+    return True, "File matches"
+
+
 def run_test(lang, test):
-    server = subprocess.Popen(["./chat_server", "0"],
-                              stdout=subprocess.PIPE)
+    # Mock peers are listening sockets handed to the server as its peer file
+    peers = []
+    args = ["./chat_server", "0"]
+    # "servers": n runs n servers federated with each other, each listing all
+    # the others in its peer file
+    count = test.get("servers", 1)
+    peer_files = []
+    if "peers" in test or count > 1:
+        for _ in range(test.get("peers", 0)):
+            peer = socket.create_server(("127.0.0.1", 0))
+            peer.settimeout(5)
+            peers.append(peer)
+        for _ in range(count):
+            with tempfile.NamedTemporaryFile("w", delete=False) as f:
+                for peer in peers:
+                    f.write("%s:%d\n" % peer.getsockname())
+            peer_files.append(f.name)
+
+    # $PEERN is the address of the Nth mock peer. $FILE names a scratch file the
+    # server writes to, whose lines are checked against the "file" patterns
+    # once the test is done, and $CONFIG a file starting out as "config".
+    names = {f"$PEER{n}": "%s:%d" % p.getsockname() for n, p in enumerate(peers)}
+    if "file" in test:
+        fd, path = tempfile.mkstemp()
+        os.close(fd)
+        names["$FILE"] = path
+    if "config" in test:
+        with tempfile.NamedTemporaryFile("w", delete=False) as f:
+            f.write(test["config"])
+        names["$CONFIG"] = f.name
+    extra = [substitute(arg, names) for arg in test.get("args", [])]
+    # "env" adds to the servers' environment
+    env = {**os.environ, **test.get("env", {})}
+
+    # A panic in any connection's task fails the test, even if the server
+    # carried on without it
+    errors = tempfile.TemporaryFile()
+    servers = []
+    ok = False
     try:
-        addr = next(server.stdout).decode().strip()
-        ok, msg = client(test, addr)
+        addrs = []
+        for n in range(count):
+            files = peer_files[n:n + 1]
+            servers.append(subprocess.Popen([*args, *files, *extra], env=env,
+                                            stdout=subprocess.PIPE, stderr=errors))
+            addrs.append(next(servers[-1].stdout).decode().strip())
+        # Only now are the servers' addresses known, so they're added to each
+        # other's peer files and dialed on SIGHUP, all at about the same time
+        if count > 1:
+            for n, path in enumerate(peer_files):
+                with open(path, "a") as f:
+                    for other, addr in enumerate(addrs):
+                        if other != n:
+                            f.write(addr + "\n")
+            for server in servers:
+                server.send_signal(signal.SIGHUP)
+        ok, msg = client(test, addrs, servers[0], peers, names)
     except StopIteration:
         return False, "Failed to read server address from standard out"
     finally:
-        server.terminate()
+        for server in servers:
+            server.terminate()
+            server.wait()
+        errors.seek(0)
+        panics = [line for line in errors.read().splitlines() if b"panicked" in line]
+        errors.close()
+        if ok and panics:
+            ok, msg = False, f"Test {test['name']} failed: the server {panics[0].decode()}"
+        for peer in peers:
+            peer.close()
+        for peer_file in peer_files:
+            os.unlink(peer_file)
+        if "file" in test:
+            if ok:
+                ok, msg = check_file(names["$FILE"], test["file"])
+            os.unlink(names["$FILE"])
+        if "config" in test:
+            os.unlink(names["$CONFIG"])
 
     return ok, msg
 
@@ -85,6 +329,9 @@ def run_tests(lang, tests):
             print("Built")
 
         for test in tests:
+            # Tests may be restricted to the implementations that support them
+            if "langs" in test and lang["name"] not in test["langs"]:
+                continue
             if ARGS.v:
                 print(f"\tRunning test: {test['name']}...", end="", flush=True)
             ok, msg = run_test(lang, test)